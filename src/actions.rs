@@ -1,8 +1,13 @@
+use crate::mdb::{capture, modules::modinfo};
+use crate::mtree::kerman::kman::{self, get_kernel_infos, KernelInfo};
 use crate::mtree::moddeps::ktree::KModuleTree;
-use crate::{mdb::modlist, pakmod};
-use crate::{mtree::kerman::kman::get_kernel_infos, pakmod::rmpak::PackMod};
+use crate::mtree::usage::{self, DiskUsage};
+use crate::{config, mdb::modlist, pakmod, pakmod::detect::PkgManager, pakmod::rmpak::PackMod, pending, syscfg, sysutils};
 
-use std::io::ErrorKind;
+use colored::Colorize;
+use regex::Regex;
+
+use std::{collections::HashSet, io::ErrorKind, path::Path};
 
 /// Show module dependency tree.
 ///
@@ -12,8 +17,8 @@ use std::io::ErrorKind;
 ///    bluetooth/hci_nokia.ko
 ///    ltc3815.ko
 ///    snd-soc-skl-ssp-clk
-pub fn do_tree(debug: &bool, modules: &[String]) {
-    for ki in get_kernel_infos(debug) {
+pub fn do_tree(debug: &bool, kernel: Option<&String>, modules: &[String]) -> Result<(), std::io::Error> {
+    for ki in get_kernels(debug, kernel)? {
         log::info!("Displaying module dependencies as a tree per a module");
         let kmtree: KModuleTree<'_> = KModuleTree::new(&ki);
         for (m, d) in kmtree.get_specified_deps(modules) {
@@ -23,121 +28,1024 @@ pub fn do_tree(debug: &bool, modules: &[String]) {
             }
         }
     }
+
+    Ok(())
 }
 
 /// List dependencies from all specified modules
 /// in a flat sorted format
-pub fn do_list(debug: &bool, modules: &[String]) -> Vec<String> {
+pub fn do_list(
+    debug: &bool, kernel: Option<&String>, modules: &[String], initramfs: bool,
+) -> Result<Vec<String>, std::io::Error> {
     let mut out: Vec<String> = Vec::default();
-    for ki in get_kernel_infos(debug) {
+    let initramfs_mods = get_initramfs_modules(debug);
+    if !initramfs_mods.is_empty() {
+        if initramfs {
+            log::info!("Including {} modules, required by the initramfs configuration", initramfs_mods.len());
+        } else {
+            log::info!(
+                "{} modules, required by the initramfs configuration, are kept as well, use --initramfs to list them",
+                initramfs_mods.len()
+            );
+        }
+    }
+
+    for ki in get_kernels(debug, kernel)? {
         let kmtree: KModuleTree<'_> = KModuleTree::new(&ki);
-        for m in kmtree.merge_specified_deps(modules) {
-            out.push(m);
+        let mut k_out: Vec<String> = kmtree.merge_specified_deps(modules).into_iter().collect();
+        if initramfs {
+            for m in ki.get_deps_for_flatten(&initramfs_mods) {
+                if !k_out.contains(&m) {
+                    k_out.push(m);
+                }
+            }
+        }
+
+        // Report disk usage as --apply would leave it, regardless of the listed modules
+        let reg_mods = get_registered_modules(debug, &modlist::ModList::new(&ki, debug)?)?;
+        let kept: HashSet<String> = get_kept_modules(debug, &ki, &reg_mods).into_iter().collect();
+        let disk_mods = ki.get_disk_modules();
+        let pruned: Vec<String> = disk_mods.iter().filter(|m| !kept.contains(*m)).cloned().collect();
+        DiskUsage::new(&ki, &disk_mods, &pruned).log(&ki.version);
+
+        for m in k_out {
+            if !out.contains(&m) {
+                out.push(m);
+            }
         }
     }
 
     out.sort();
-    out
+    Ok(out)
 }
 
-/// Add or remove kernel modules
-fn _add_remove(debug: &bool, add: bool, is_static: bool, modules: &mut Vec<String>) -> Result<(), std::io::Error> {
-    for ki in get_kernel_infos(debug) {
-        let kmtree: KModuleTree<'_> = KModuleTree::new(&ki);
-        let rml: Result<modlist::ModList<'_>, std::io::Error> = modlist::ModList::new(&ki, debug);
+/// Display cost of each registered module: size of its dependency closure and size
+/// of the part, which only it keeps (i.e. freed once it is removed), most expensive first.
+pub fn do_cost(debug: &bool, kernel: Option<&String>) -> Result<(), std::io::Error> {
+    let implicit = get_implicit_modules(debug);
+    for ki in get_kernels(debug, kernel)? {
+        let ml = modlist::ModList::new(&ki, debug)?;
+        let costs = usage::get_costs(&ki, &get_registered_modules(debug, &ml)?, &implicit);
+        log::info!("Cost of {} registered modules of kernel {}", costs.len(), ki.version);
 
-        if rml.is_err() {
-            return Err(rml.err().unwrap());
+        println!("{:>10} {:>10} {:>7}  MODULE", "FREED", "CLOSURE", "MODULES");
+        for c in costs {
+            println!(
+                "{:>10} {:>10} {:>7}  {}",
+                usage::format_size(c.exclusive),
+                usage::format_size(c.closure),
+                c.modules,
+                c.name
+            );
         }
+    }
 
-        // Use lsmod?
-        if modules.is_empty() {
-            for s in kmtree.get_loaded_modules().iter().map(|x| x.to_owned()).collect::<Vec<String>>() {
-                modules.push(s);
+    Ok(())
+}
+
+/// Compare modules, loaded now, with modules, kept for the running kernel (registered ones,
+/// those kept implicitly, and their dependencies). Loaded modules, those are not kept, would be
+/// removed from the disk by the next commit, so this is an error.
+pub fn do_status(debug: &bool) -> Result<(), std::io::Error> {
+    if !Path::new("/proc/modules").exists() {
+        return Err(std::io::Error::new(ErrorKind::NotFound, "Unable to get loaded modules: /proc/modules is not found"));
+    }
+
+    let ki = match get_kernels(debug, Some(&"running".to_string()))?.pop() {
+        Some(ki) => ki,
+        None => return Err(std::io::Error::new(ErrorKind::NotFound, "No modules found for the running kernel")),
+    };
+    let ml = modlist::ModList::new(&ki, debug)?;
+    let reg_mods = get_registered_modules(debug, &ml)?;
+    let mut kept_mods = reg_mods.to_owned();
+    kept_mods.extend(get_implicit_modules(debug));
+
+    let kept: Vec<String> = ki.get_deps_for_flatten(&kept_mods).iter().map(|m| kman::get_module_name(m)).collect();
+    let loaded: Vec<String> = modinfo::lsmod().into_iter().map(|m| kman::get_module_name(&m.name)).collect();
+
+    let mut unregistered = 0;
+    for name in loaded.iter().filter(|m| !kept.contains(m)) {
+        match ki.find_module(name) {
+            Some(path) => {
+                log::error!(
+                    "Module \"{}\" is loaded, but not registered, so it would be removed: {}",
+                    name.bright_yellow(),
+                    path
+                );
+                unregistered += 1;
             }
+            None => log::warn!("Module \"{}\" is loaded, but not found on the disk", name.bright_yellow()),
         }
+    }
 
-        modules.sort();
+    let mut unloaded = 0;
+    for m in reg_mods.iter().filter(|m| !kman::is_module_pattern(m) && !loaded.contains(&kman::get_module_name(m))) {
+        log::info!("Module \"{}\" is registered, but not loaded", m.bright_yellow());
+        unloaded += 1;
+    }
 
-        log::info!("Scope of {} modules", modules.len());
+    log::info!(
+        "Kernel {}: loaded modules {}, kept {}, loaded but not registered {}, registered but not loaded {}",
+        ki.version,
+        loaded.len(),
+        kept.len(),
+        unregistered,
+        unloaded
+    );
 
-        let mut ml: modlist::ModList<'_> = rml.unwrap();
-        for modname in &mut *modules {
-            if add {
-                ml.add(modname.to_string(), is_static);
-            } else {
-                let res = ml.remove(modname.to_string());
-                if res.is_err() {
-                    return Err(std::io::Error::new(ErrorKind::InvalidInput, res.err().unwrap()));
-                }
-            };
+    if unregistered > 0 {
+        return Err(std::io::Error::new(
+            ErrorKind::Other,
+            format!("{} loaded modules are not registered and would be removed on the next --apply", unregistered),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Get kernels to operate on: either all found in the system (by default or "all"),
+/// the running one ("running"), the latest one ("latest") or the specified version.
+fn get_kernels<'a>(debug: &'a bool, kernel: Option<&String>) -> Result<Vec<KernelInfo<'a>>, std::io::Error> {
+    let kinfos = get_kernel_infos(debug);
+    let kernel = match kernel.map(|k| k.as_str()) {
+        None | Some("all") => return Ok(kinfos),
+        Some("latest") => {
+            return Ok(kinfos.into_iter().max_by(|a, b| sysutils::version_cmp(&a.version, &b.version)).into_iter().collect())
         }
+        Some("running") => sysutils::get_running_kernel()?,
+        Some(kernel) => kernel.to_string(),
+    };
+
+    match kinfos.into_iter().find(|ki| ki.version == kernel) {
+        Some(ki) => Ok(vec![ki]),
+        None => Err(std::io::Error::new(ErrorKind::NotFound, format!("No modules found for kernel \"{}\"", kernel))),
+    }
+}
+
+/// Add or remove modules in a module list and save it
+fn _update_modlist(ml: &mut modlist::ModList, add: bool, is_static: bool, modules: &[String]) -> Result<(), std::io::Error> {
+    log::info!("Scope of {} modules", modules.len());
+
+    for modname in modules {
+        if add {
+            ml.add(modname.to_string(), is_static);
+        } else {
+            let res = ml.remove(modname.to_string());
+            if res.is_err() {
+                return Err(std::io::Error::new(ErrorKind::InvalidInput, res.err().unwrap()));
+            }
+        };
+    }
 
-        let res = ml.save();
-        if res.is_err() {
-            return Err(res.err().unwrap());
+    ml.save()
+}
+
+/// Expand module patterns against modules of a kernel, keeping other modules as is
+fn expand_modules(ki: &KernelInfo, modules: &[String]) -> Result<Vec<String>, std::io::Error> {
+    let mut out: Vec<String> = vec![];
+    for modname in modules {
+        if !kman::is_module_pattern(modname) {
+            if !out.contains(modname) {
+                out.push(modname.to_owned());
+            }
+            continue;
+        }
+
+        let found = ki.find_modules(&kman::ModPattern::new(modname)?);
+        if found.is_empty() {
+            log::warn!("No modules found in kernel {}, matching \"{}\"", ki.version, modname);
+        } else {
+            log::info!("Pattern \"{}\" matches {} modules in kernel {}", modname, found.len(), ki.version);
+        }
+
+        for m in found {
+            if !out.contains(m) {
+                out.push(m.to_owned());
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// Expand module patterns against modules, registered in a module list.
+/// Patterns, those are registered as they are, are kept.
+fn expand_registered(ml: &modlist::ModList, modules: &[String]) -> Result<Vec<String>, std::io::Error> {
+    let registered: Vec<String> = ml.get_entries().into_iter().map(|(name, _)| name).collect();
+    let mut out: Vec<String> = vec![];
+    for modname in modules {
+        if !kman::is_module_pattern(modname) || registered.contains(modname) {
+            if !out.contains(modname) {
+                out.push(modname.to_owned());
+            }
+            continue;
+        }
+
+        let pattern = kman::ModPattern::new(modname)?;
+        let found: Vec<&String> = registered.iter().filter(|name| pattern.matches(name)).collect();
+        if found.is_empty() {
+            return Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("No registered modules found, matching \"{}\"", modname),
+            ));
+        }
+
+        for m in found {
+            if !out.contains(m) {
+                out.push(m.to_owned());
+            }
         }
     }
 
+    Ok(out)
+}
+
+/// Add or remove kernel modules.
+/// Module patterns are expanded, unless they should be stored as they are.
+fn _add_remove(
+    debug: &bool, kernel: Option<&String>, global: bool, add: bool, is_static: bool, store_pattern: bool,
+    modules: &mut Vec<String>,
+) -> Result<(), std::io::Error> {
+    // Use lsmod? Blacklisted modules, those happen to be loaded, are not needed.
+    let blacklisted = get_blacklisted_modules(debug);
+    if modules.is_empty() {
+        for name in modinfo::lsmod().into_iter().map(|modinfo| modinfo.name) {
+            match find_conf_module(&blacklisted, &name) {
+                Some(cm) => log::info!("Skipping loaded module \"{}\", blacklisted by {}", name.bright_yellow(), cm.source),
+                None => modules.push(name),
+            }
+        }
+    } else if add {
+        warn_blacklisted(&blacklisted, modules);
+    }
+
+    // Global list is keyed by module names (or patterns), regardless of their paths in a specific kernel
+    if global {
+        let mut names: Vec<String> =
+            modules.iter().map(|m| if kman::is_module_pattern(m) { m.to_owned() } else { kman::get_module_name(m) }).collect();
+        names.sort();
+        names.dedup();
+
+        let mut ml = modlist::ModList::global(debug)?;
+        let names = if add { names } else { expand_registered(&ml, &names)? };
+        return _update_modlist(&mut ml, add, is_static, &names);
+    }
+
+    modules.sort();
+    for ki in get_kernels(debug, kernel)? {
+        let mut ml = modlist::ModList::new(&ki, debug)?;
+        let k_modules = if !add {
+            expand_registered(&ml, modules)?
+        } else if store_pattern {
+            modules.to_owned()
+        } else {
+            expand_modules(&ki, modules)?
+        };
+        _update_modlist(&mut ml, add, is_static, &k_modules)?;
+    }
+
     Ok(())
 }
 
+/// Refuse to read loaded modules or devices in a container, as these are those of the host,
+/// unless explicitly allowed
+fn check_container_host(what: &str, hint: &str, allow_container: bool) -> Result<(), std::io::Error> {
+    if let Some(container) = sysutils::get_container() {
+        if !allow_container {
+            return Err(std::io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{} are those of the host in a container ({}), {}", what, container, hint),
+            ));
+        }
+        log::warn!("Running in a container ({}), using {} of the host", container.bright_yellow(), what.to_lowercase());
+    }
+
+    Ok(())
+}
+
+/// Refuse to use loaded modules (i.e. when no modules are specified) in a container,
+/// as these are modules of the host, unless explicitly allowed
+fn check_container_snapshot(modules: &[String], allow_container: bool) -> Result<(), std::io::Error> {
+    if !modules.is_empty() {
+        return Ok(());
+    }
+
+    check_container_host("Loaded modules", "specify modules or use --allow-container", allow_container)
+}
+
 /// Add (register) kernel modules to be preserved
-pub fn do_add(debug: &bool, is_static: bool, modules: &[String]) -> Result<(), std::io::Error> {
-    _add_remove(debug, true, is_static, &mut modules.iter().map(|x| x.to_string()).collect())
+pub fn do_add(
+    debug: &bool, kernel: Option<&String>, global: bool, is_static: bool, store_pattern: bool, allow_container: bool,
+    modules: &[String],
+) -> Result<(), std::io::Error> {
+    check_container_snapshot(modules, allow_container)?;
+    _add_remove(debug, kernel, global, true, is_static, store_pattern, &mut modules.iter().map(|x| x.to_string()).collect())
 }
 
 /// Remove (unregister) kernel modules from being preserved
-pub fn do_remove(debug: &bool, modules: &[String]) -> Result<(), std::io::Error> {
-    _add_remove(debug, false, false, &mut modules.iter().map(|x| x.to_string()).collect())
-}
-
-/// Commit changes on the disk. This will permanently remove unused kernel modules
-/// from the disk.
-pub fn do_commit(debug: &bool) -> Result<(), std::io::Error> {
-    for ki in get_kernel_infos(debug) {
-        match modlist::ModList::new(&ki, debug) {
-            Ok(ml) => {
-                let mut diff_mods: Vec<String> = vec![];
-                let idx_mods = ki.get_deps_for_flatten(&ml.get_modules());
-                let disk_mods = ki.get_disk_modules();
-
-                for dmod in &disk_mods {
-                    if !idx_mods.contains(dmod) {
-                        diff_mods.push(dmod.to_owned());
+pub fn do_remove(
+    debug: &bool, kernel: Option<&String>, global: bool, allow_container: bool, modules: &[String],
+) -> Result<(), std::io::Error> {
+    check_container_snapshot(modules, allow_container)?;
+    _add_remove(debug, kernel, global, false, false, false, &mut modules.iter().map(|x| x.to_string()).collect())
+}
+
+/// Exclude kernel modules from being preserved in specific kernels, even if they are
+/// registered globally. Exclusion is dropped by removing the module.
+pub fn do_exclude(debug: &bool, kernel: Option<&String>, modules: &[String]) -> Result<(), std::io::Error> {
+    for ki in get_kernels(debug, kernel)? {
+        let mut ml = modlist::ModList::new(&ki, debug)?;
+        for modname in modules {
+            ml.exclude(modname.to_string());
+        }
+        ml.save()?;
+    }
+
+    Ok(())
+}
+
+/// Returns true if a module (by its name or path) is in the list of modules
+fn is_listed(modules: &[String], name: &str) -> bool {
+    let name = kman::get_module_name(name);
+    modules.iter().any(|m| kman::get_module_name(m) == name)
+}
+
+/// Capture loaded modules, modaliases of devices, kernel version and command line
+/// of the running system to a file, so it can be imported into another image.
+pub fn do_capture(path: &str, allow_container: bool) -> Result<(), std::io::Error> {
+    check_container_host("Loaded modules and devices", "use --allow-container", allow_container)?;
+    let cap = capture::Capture::take()?;
+    cap.save(path)?;
+    log::info!(
+        "Captured {} modules and {} modaliases of kernel {} to {}",
+        cap.modules.len(),
+        cap.modaliases.len(),
+        cap.kernel,
+        path.bright_yellow()
+    );
+
+    Ok(())
+}
+
+/// Get modules of a capture, those are found in a kernel, optionally also modules,
+/// matching captured modaliases in modules.alias of the kernel
+fn get_captured_modules(ki: &KernelInfo, cap: &capture::Capture, modaliases: bool) -> Vec<String> {
+    if ki.version != cap.kernel {
+        log::info!("Mapping modules of kernel {} to kernel {} by their names", cap.kernel, ki.version);
+    }
+
+    let mut out: Vec<String> = vec![];
+    for m in &cap.modules {
+        match ki.find_module(m) {
+            Some(_) => out.push(m.to_owned()),
+            None => log::warn!("Captured module \"{}\" is not found in kernel {}, skipping", m.bright_yellow(), ki.version),
+        }
+    }
+
+    if modaliases {
+        match capture::resolve_modaliases(ki, &cap.modaliases) {
+            Ok(resolved) => {
+                log::info!("Resolved {} modaliases to {} modules in kernel {}", cap.modaliases.len(), resolved.len(), ki.version);
+                for m in resolved {
+                    if !is_listed(&out, &m) {
+                        out.push(m);
                     }
                 }
+            }
+            Err(err) => log::warn!("Unable to resolve modaliases in kernel {}: {}", ki.version, err),
+        }
+    }
 
-                log::info!("Modules on disk: {}, indexed: {}, to remove: {}", disk_mods.len(), idx_mods.len(), diff_mods.len());
-                match ml.commit(&diff_mods) {
-                    Ok(_) => ml.vacuum_dirs()?,
-                    Err(err) => {
-                        return Err(std::io::Error::new(err.kind(), format!("Unable to commit changes to the disk: {}", err)))
-                    }
+    out
+}
+
+/// Import a capture of a reference machine, registering its modules as static ones.
+/// Modules are mapped by their names to the target kernels. Optionally modaliases
+/// of its devices are resolved to modules with modules.alias of the target kernels.
+pub fn do_import_capture(
+    debug: &bool, kernel: Option<&String>, global: bool, path: &str, modaliases: bool,
+) -> Result<(), std::io::Error> {
+    let cap = capture::Capture::load(path)?;
+    if cap.modules.is_empty() && cap.modaliases.is_empty() {
+        return Err(std::io::Error::new(ErrorKind::InvalidData, format!("No modules found in capture {}", path)));
+    }
+    log::info!("Importing {} modules, captured on kernel {}", cap.modules.len(), cap.kernel);
+
+    // Global list is keyed by module names, so captured modules are kept as they are
+    if global {
+        let mut modules = cap.modules.to_owned();
+        if modaliases {
+            for ki in get_kernels(debug, kernel)? {
+                match capture::resolve_modaliases(&ki, &cap.modaliases) {
+                    Ok(resolved) => modules.extend(resolved),
+                    Err(err) => log::warn!("Unable to resolve modaliases in kernel {}: {}", ki.version, err),
                 }
             }
+        }
+        return _add_remove(debug, kernel, global, true, true, false, &mut modules);
+    }
 
-            Err(err) => {
+    for ki in get_kernels(debug, kernel)? {
+        let mut modules = get_captured_modules(&ki, &cap, modaliases);
+        if modules.is_empty() {
+            log::warn!("No captured modules found in kernel {}", ki.version);
+            continue;
+        }
+        _add_remove(debug, Some(&ki.version), false, true, true, false, &mut modules)?;
+    }
+
+    Ok(())
+}
+
+/// Find a module by its name or path among configured modules
+fn find_conf_module<'a>(modules: &'a [syscfg::ConfModule], name: &str) -> Option<&'a syscfg::ConfModule> {
+    let name = kman::get_module_name(name);
+    modules.iter().find(|cm| kman::get_module_name(&cm.name) == name)
+}
+
+/// Get modules, blacklisted in modprobe.d configuration
+fn get_blacklisted_modules(debug: &bool) -> Vec<syscfg::ConfModule> {
+    let out = syscfg::modprobe::get_blacklisted(debug);
+    if *debug {
+        for cm in &out {
+            log::debug!("Module \"{}\" is blacklisted by {}", cm.name, cm.source);
+        }
+    }
+
+    out
+}
+
+/// Warn about registered modules, those are blacklisted
+fn warn_blacklisted(blacklisted: &[syscfg::ConfModule], modules: &[String]) {
+    for m in modules.iter().filter(|m| !kman::is_module_pattern(m)) {
+        if let Some(cm) = find_conf_module(blacklisted, m) {
+            log::warn!("Module \"{}\" is registered, but it is blacklisted by {}", m.bright_yellow(), cm.source);
+        }
+    }
+}
+
+/// Get modules (or patterns), required by the configuration of initramfs generators.
+/// These are implicitly static and are never removed.
+fn get_initramfs_modules(debug: &bool) -> Vec<String> {
+    let mut out: Vec<String> = vec![];
+    for im in syscfg::initramfs::get_modules(debug) {
+        if *debug {
+            log::debug!("Module \"{}\" is required by {}", im.name, im.source);
+        }
+
+        if !out.contains(&im.name) {
+            out.push(im.name);
+        }
+    }
+
+    out
+}
+
+/// Get modules, configured to be loaded at boot, each once
+fn get_autoload_modules(debug: &bool) -> Vec<syscfg::ConfModule> {
+    let mut out: Vec<syscfg::ConfModule> = vec![];
+    for cm in syscfg::modload::get_modules(debug) {
+        if *debug {
+            log::debug!("Module \"{}\" is loaded at boot by {}", cm.name, cm.source);
+        }
+
+        if find_conf_module(&out, &cm.name).is_none() {
+            out.push(cm);
+        }
+    }
+
+    out
+}
+
+/// Import modules, configured to be loaded at boot (/etc/modules, modules-load.d and
+/// the kernel command line), so they are kept permanently. Modules, those are already
+/// registered, are left as they are.
+pub fn do_import_autoload(debug: &bool, kernel: Option<&String>, global: bool) -> Result<(), std::io::Error> {
+    let modules = get_autoload_modules(debug);
+    if modules.is_empty() {
+        log::info!("No modules are configured to be loaded at boot");
+        return Ok(());
+    }
+
+    let import = |ml: &mut modlist::ModList, name: &str, cm: &syscfg::ConfModule| {
+        if ml.set(name.to_string(), modlist::AUTOLOAD) {
+            log::info!("Importing module \"{}\", loaded at boot by {}", name.bright_yellow(), cm.source);
+        } else if *debug {
+            log::debug!("Module \"{}\" is already registered", name);
+        }
+    };
+
+    if global {
+        let mut ml = modlist::ModList::global(debug)?;
+        for cm in &modules {
+            import(&mut ml, &kman::get_module_name(&cm.name), cm);
+        }
+        return ml.save();
+    }
+
+    for ki in get_kernels(debug, kernel)? {
+        let mut ml = modlist::ModList::new(&ki, debug)?;
+        for cm in &modules {
+            match ki.find_module(&cm.name) {
+                Some(path) => import(&mut ml, path, cm),
+                None => log::warn!("Module \"{}\", loaded at boot by {}, not found in kernel {}", cm.name, cm.source, ki.version),
+            }
+        }
+        ml.save()?;
+    }
+
+    Ok(())
+}
+
+/// Get modules, registered for a kernel and globally, those are kept. Modules never to keep
+/// are skipped, unless they are also to keep always.
+fn get_registered_modules(debug: &bool, ml: &modlist::ModList) -> Result<Vec<String>, std::io::Error> {
+    let cfg = config::get();
+    Ok(ml
+        .get_effective_modules(&modlist::ModList::global(debug)?)
+        .into_iter()
+        .filter(|m| !is_listed(&cfg.never_keep, m) || is_listed(&cfg.always_keep, m))
+        .collect())
+}
+
+/// Get modules, those are kept regardless of being registered: modules to keep always
+/// and those required by the initramfs configuration
+fn get_implicit_modules(debug: &bool) -> Vec<String> {
+    let mut out = config::get().always_keep;
+    for m in get_initramfs_modules(debug) {
+        if !out.contains(&m) {
+            out.push(m);
+        }
+    }
+
+    out
+}
+
+/// Get modules of a kernel, those are kept: registered ones, those kept implicitly, and their dependencies
+fn get_kept_modules(debug: &bool, ki: &KernelInfo, reg_mods: &[String]) -> Vec<String> {
+    let mut mods = reg_mods.to_vec();
+    for m in get_implicit_modules(debug) {
+        if !mods.contains(&m) {
+            mods.push(m);
+        }
+    }
+
+    ki.get_deps_for_flatten(&mods)
+}
+
+/// Get modules of a kernel, those are not used and are subject to be removed from the disk
+fn get_unused_modules(debug: &bool, ki: &KernelInfo) -> Result<Vec<String>, std::io::Error> {
+    match modlist::ModList::new(ki, debug) {
+        Ok(ml) => {
+            let cfg = config::get();
+            let mut diff_mods: Vec<String> = vec![];
+
+            let reg_mods = get_registered_modules(debug, &ml)?;
+            warn_blacklisted(&get_blacklisted_modules(debug), &reg_mods);
+
+            let idx_mods = get_kept_modules(debug, ki, &reg_mods);
+            for m in &idx_mods {
+                if is_listed(&cfg.never_keep, m) && !is_listed(&cfg.always_keep, m) {
+                    log::warn!("Module \"{}\" is never to keep, but it is required by other modules", m.bright_yellow());
+                }
+            }
+            let disk_mods = ki.get_disk_modules();
+            let kept: HashSet<&String> = idx_mods.iter().collect();
+
+            for dmod in &disk_mods {
+                if !kept.contains(dmod) {
+                    diff_mods.push(dmod.to_owned());
+                }
+            }
+
+            log::info!("Modules on disk: {}, indexed: {}, to remove: {}", disk_mods.len(), idx_mods.len(), diff_mods.len());
+            DiskUsage::new(ki, &disk_mods, &diff_mods).log(&ki.version);
+            Ok(diff_mods)
+        }
+
+        Err(err) => {
+            Err(std::io::Error::new(err.kind(), format!("Error while getting module list on kernel \"{}\": {}", ki.version, err)))
+        }
+    }
+}
+
+/// Get names of modules of the running kernel, those are loaded or needed to boot,
+/// each with a reason
+fn get_vital_modules() -> Vec<(String, String)> {
+    let mut out: Vec<(String, String)> = vec![];
+    if Path::new("/proc/modules").exists() {
+        out.extend(modinfo::lsmod().into_iter().map(|m| (kman::get_module_name(&m.name), "loaded".to_string())));
+    }
+
+    for (m, mp) in sysutils::get_boot_modules() {
+        out.push((kman::get_module_name(&m), format!("needed to mount {}", mp)));
+    }
+
+    out
+}
+
+/// Get unused modules of the kernels, those are to be removed by a commit, checking them
+/// before anything is changed. Nothing is to be removed at all, if any protected module
+/// would be removed, or any module, configured to be loaded at boot, or any module of the
+/// running kernel, which is loaded or needed to boot, unless forced. Modules of the running kernel are never removed in a container.
+fn check_commit(debug: &bool, kinfos: &[KernelInfo], force: bool) -> Result<Vec<Vec<String>>, std::io::Error> {
+    let mut patterns: Vec<kman::ModPattern> = vec![];
+    for p in config::get().get_protected() {
+        patterns.push(kman::ModPattern::new(&p)?);
+    }
+
+    let autoload = get_autoload_modules(debug);
+    let running = sysutils::get_running_kernel().ok();
+
+    // Modules of the running kernel are those of the host, if running in a container
+    if let Some(container) = sysutils::get_container() {
+        if let Some(ki) = kinfos.iter().find(|ki| running.as_ref() == Some(&ki.version)) {
+            return Err(std::io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "Refusing to remove modules of the host kernel {} in a container ({}), use --kernel to select other kernels",
+                    ki.version, container
+                ),
+            ));
+        }
+    }
+    let vital_mods = if kinfos.iter().any(|ki| running.as_ref() == Some(&ki.version)) { get_vital_modules() } else { vec![] };
+
+    let mut unused: Vec<Vec<String>> = vec![];
+    let mut protected: Vec<String> = vec![];
+    let mut vital: Vec<String> = vec![];
+    let mut boot: Vec<String> = vec![];
+    for ki in kinfos {
+        let diff_mods = get_unused_modules(debug, ki)?;
+        for m in &diff_mods {
+            if patterns.iter().any(|p| p.matches(m)) {
+                protected.push(format!("{} ({})", m, ki.version));
+            }
+
+            if running.as_ref() == Some(&ki.version) {
+                if let Some((_, reason)) = vital_mods.iter().find(|(name, _)| *name == kman::get_module_name(m)) {
+                    vital.push(format!("{} ({}, {})", m, ki.version, reason));
+                }
+            }
+
+            if let Some(cm) = find_conf_module(&autoload, m) {
+                boot.push(format!("{} ({}, loaded at boot by {})", m, ki.version, cm.source));
+            }
+        }
+        unused.push(diff_mods);
+    }
+
+    // Report all the modules at once, so they can be registered in one go
+    if !force && (!protected.is_empty() || !vital.is_empty() || !boot.is_empty()) {
+        for m in &protected {
+            log::error!("Protected module would be removed: {}", m.bright_yellow());
+        }
+        for m in &vital {
+            log::error!("Module of the running kernel would be removed: {}", m.bright_yellow());
+        }
+        for m in &boot {
+            log::error!("Module, loaded at boot, would be removed: {}", m.bright_yellow());
+        }
+        if !boot.is_empty() {
+            log::warn!("Modules, loaded at boot, can be registered with \"limopack --import-autoload\"");
+        }
+
+        let mut refused: Vec<String> = vec![];
+        if !protected.is_empty() {
+            refused.push(format!("{} protected modules", protected.len()));
+        }
+        if !vital.is_empty() {
+            refused.push(format!("{} loaded or boot modules of the running kernel", vital.len()));
+        }
+        if !boot.is_empty() {
+            refused.push(format!("{} modules loaded at boot", boot.len()));
+        }
+        return Err(std::io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!("Refusing to remove {}, use --force to remove them anyway", refused.join(" and ")),
+        ));
+    }
+
+    if !protected.is_empty() {
+        log::warn!("Removing {} protected modules, as forced", protected.len().to_string().bright_yellow());
+    }
+    if !vital.is_empty() {
+        log::warn!(
+            "Removing {} loaded or boot modules of the running kernel, as forced",
+            vital.len().to_string().bright_yellow()
+        );
+    }
+    if !boot.is_empty() {
+        log::warn!("Removing {} modules, loaded at boot, as forced", boot.len().to_string().bright_yellow());
+    }
+
+    Ok(unused)
+}
+
+/// Permanently remove unused modules of the kernels, as they are returned by `check_commit`
+fn remove_unused(debug: &bool, kinfos: &[KernelInfo], unused: Vec<Vec<String>>) -> Result<(), std::io::Error> {
+    let cfg = config::get();
+    for (ki, diff_mods) in kinfos.iter().zip(unused) {
+        let ml = modlist::ModList::new(ki, debug)?;
+        match ml.commit(&diff_mods, cfg.quarantine.as_deref().map(Path::new)) {
+            Ok(_) => ml.vacuum_dirs()?,
+            Err(err) => return Err(std::io::Error::new(err.kind(), format!("Unable to commit changes to the disk: {}", err))),
+        }
+    }
+
+    Ok(())
+}
+
+/// Commit changes on the disk for the kernels. This will permanently remove their unused
+/// modules from the disk.
+fn commit_kernels(debug: &bool, kinfos: &[KernelInfo], force: bool) -> Result<(), std::io::Error> {
+    let unused = check_commit(debug, kinfos, force)?;
+    remove_unused(debug, kinfos, unused)
+}
+
+/// Unregister packages and commit changes on the disk. Unused modules are checked before
+/// any package is unregistered, so nothing is changed at all, if the commit is refused.
+pub fn do_apply(
+    debug: &bool, pm: PkgManager, root: &Path, kernel: Option<&String>, pkgnames: &[String], force: bool,
+) -> Result<(), std::io::Error> {
+    let kinfos = get_kernels(debug, kernel)?;
+    let unused = check_commit(debug, &kinfos, force)?;
+    do_unregister_pkg(debug, pm, root, pkgnames)?;
+    remove_unused(debug, &kinfos, unused)
+}
+
+/// Carry registered modules over to a newly installed kernel. This is meant to be called
+/// from /etc/kernel/postinst.d hook, once the new kernel and its modules.dep are in place.
+///
+/// Modules are taken from the specified kernel, otherwise from the latest other kernel,
+/// which has registered modules. Each module is resolved in the new kernel by its path,
+/// then by its name, so renamed (moved or compressed) modules are followed. Modules,
+/// those no longer exist in the new kernel, are dropped.
+pub fn do_kernel_postinst(
+    debug: &bool, version: &str, from: Option<&String>, prune: bool, force: bool,
+) -> Result<(), std::io::Error> {
+    let new_ki = KernelInfo::new(version, debug);
+    if !new_ki.is_valid() {
+        return Err(std::io::Error::new(ErrorKind::NotFound, format!("No modules found for kernel \"{}\"", version)));
+    }
+
+    let kinfos = get_kernel_infos(debug);
+    let old_ki = match from {
+        Some(from) => {
+            if from == version {
                 return Err(std::io::Error::new(
-                    err.kind(),
-                    format!("Error while getting module list on kernel \"{}\": {}", ki.version, err),
+                    ErrorKind::InvalidInput,
+                    format!("Modules cannot be migrated from kernel \"{}\" to itself", version),
                 ));
             }
+            match kinfos.iter().find(|ki| ki.version == *from) {
+                Some(ki) => Some(ki),
+                None => {
+                    return Err(std::io::Error::new(ErrorKind::NotFound, format!("No modules found for kernel \"{}\"", from)))
+                }
+            }
+        }
+        None => kinfos
+            .iter()
+            .filter(|ki| ki.version != version && modlist::has_storage(ki))
+            .max_by(|a, b| sysutils::version_cmp(&a.version, &b.version)),
+    };
+
+    let mut new_ml = modlist::ModList::new(&new_ki, debug)?;
+    match old_ki {
+        Some(old_ki) => {
+            log::info!("Migrating modules from kernel {} to {}", old_ki.version.bright_yellow(), version.bright_yellow());
+            let old_ml = modlist::ModList::new(old_ki, debug)?;
+            let (mut kept, mut renamed, mut vanished): (usize, Vec<(String, String)>, Vec<String>) = (0, vec![], vec![]);
+
+            for (name, state) in old_ml.get_entries() {
+                let n_name = if kman::is_module_pattern(&name) {
+                    // Patterns are matched against modules of each kernel anyway
+                    Some(&name)
+                } else if name.contains('/') {
+                    // Path is kept as long as it is the same module, otherwise looked up by the module name
+                    new_ki.find_module(&name).or_else(|| new_ki.find_module(name.rsplit('/').next().unwrap()))
+                } else if new_ki.find_module(&name).is_some() || old_ki.find_module(&name).is_none() {
+                    // Names are kept as is, also those only "modinfo" knows about
+                    Some(&name)
+                } else {
+                    None
+                };
+
+                match n_name {
+                    Some(n_name) => {
+                        if *n_name == name {
+                            kept += 1;
+                        } else {
+                            renamed.push((name.to_owned(), n_name.to_owned()));
+                        }
+
+                        if !new_ml.set(n_name.to_owned(), state) && *debug {
+                            log::debug!("Module \"{}\" is already registered for kernel {}", n_name, version);
+                        }
+                    }
+                    None => vanished.push(name),
+                }
+            }
+
+            for (name, n_name) in &renamed {
+                log::warn!("Module \"{}\" is renamed to \"{}\"", name.bright_yellow(), n_name.bright_yellow());
+            }
+            for name in &vanished {
+                log::warn!("Module \"{}\" no longer exists in kernel {}", name.bright_yellow(), version);
+            }
+            log::info!(
+                "Kept: {}, renamed: {}, vanished: {}",
+                kept.to_string().bright_yellow(),
+                renamed.len().to_string().bright_yellow(),
+                vanished.len().to_string().bright_yellow()
+            );
+
+            new_ml.save()?;
+        }
+        None => log::warn!("No other kernel with registered modules found, nothing to migrate"),
+    }
+
+    if prune {
+        // Nothing registered would mean removing all the modules
+        if new_ml.get_effective_modules(&modlist::ModList::global(debug)?).is_empty() {
+            log::warn!("No modules are registered for kernel {}, skipping removal of unused modules", version);
+        } else {
+            commit_kernels(debug, std::slice::from_ref(&new_ki), force)?;
         }
     }
+
     Ok(())
 }
 
-/// Unregister specified package from the package manager database.
+/// Expand package name patterns, such as "linux-modules-*-generic" against packages
+/// in the package manager database. Multi-arch qualifier, such as ":amd64" is kept.
+fn expand_pkg_names(pmod: &dyn PackMod, pkgnames: &[String]) -> Result<Vec<String>, std::io::Error> {
+    let mut out: Vec<String> = vec![];
+    let mut known: Vec<String> = vec![];
+
+    for pkgname in pkgnames {
+        if !sysutils::is_wildcard(pkgname) {
+            if !out.contains(pkgname) {
+                out.push(pkgname.to_owned());
+            }
+            continue;
+        }
+
+        if known.is_empty() {
+            known = pmod.list_packages();
+            known.sort();
+            known.dedup();
+        }
+
+        let (pattern, arch) = match pkgname.split_once(':') {
+            Some((pattern, arch)) => (pattern, format!(":{}", arch)),
+            None => (pkgname.as_str(), "".to_string()),
+        };
+
+        let re = match Regex::new(&format!("^{}$", sysutils::glob_to_regex(pattern))) {
+            Ok(re) => re,
+            Err(err) => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid package pattern \"{}\": {}", pkgname, err),
+                ))
+            }
+        };
+        let matched: Vec<String> = known.iter().filter(|n| re.is_match(n)).map(|n| format!("{}{}", n, arch)).collect();
+        if matched.is_empty() {
+            return Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("No packages found in the database, matching \"{}\"", pkgname),
+            ));
+        }
+
+        log::info!("Pattern \"{}\" matches {} packages", pkgname, matched.len());
+        for m in matched {
+            if !out.contains(&m) {
+                out.push(m);
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// Unregister packages from the database of the package manager, which is locked by the caller
+fn unregister_pkgs(debug: &bool, pm: PkgManager, root: &Path, pkgnames: &[String]) -> Result<(), std::io::Error> {
+    let mut pmod = pm.get_packmod(debug, root);
+
+    for pkgname in expand_pkg_names(pmod.as_ref(), pkgnames)? {
+        if *debug {
+            log::debug!("Unregistering {} package from {} database", pkgname, pm);
+        }
+        pmod.remove_package(pkgname)?;
+    }
+
+    pmod.save()
+}
+
+/// Unregister specified packages from the database of the selected package manager
+/// in the root. Packages are removed all at once, so if any of them is not found,
+/// nothing is changed.
 /// Yuck!...
-pub fn do_unregister_pkg(debug: &bool, pkgname: &String) -> Result<(), std::io::Error> {
+pub fn do_unregister_pkg(debug: &bool, pm: PkgManager, root: &Path, pkgnames: &[String]) -> Result<(), std::io::Error> {
+    let _lock = pm.lock(debug, root, false)?;
+    unregister_pkgs(debug, pm, root, pkgnames)
+}
+
+/// Register back a package to the package manager database, which was previously
+/// unregistered, so the package manager owns its content again.
+pub fn do_reregister_pkg(debug: &bool, pm: PkgManager, root: &Path, pkgname: &String) -> Result<(), std::io::Error> {
     if *debug {
-        log::debug!("Unregistering {} package", pkgname);
+        log::debug!("Registering back {} package to {} database", pkgname, pm);
     }
 
-    let mut pmod = pakmod::dpkgmod::DpkgMod::new(debug);
-    match pmod.remove_package(pkgname.to_string()) {
+    let _lock = pm.lock(debug, root, false)?;
+    let mut pmod = pm.get_packmod(debug, root);
+    match pmod.restore_package(pkgname.to_string()) {
         Ok(_) => pmod.save(),
         Err(err) => Err(err),
     }
 }
+
+/// Returns true if applying changes must be deferred: running within a dpkg transaction
+/// makes no sense to unregister anything, as dpkg will overwrite its database at the end anyway.
+pub fn must_defer(pm: PkgManager) -> bool {
+    pakmod::dpkglock::in_transaction() && pm == PkgManager::Dpkg
+}
+
+/// Record unregistering packages and committing changes to the disk as pending, together
+/// with the options they are to be performed with, and activate the trigger, which will
+/// perform them later.
+pub fn do_defer(
+    debug: &bool, pm: PkgManager, root: Option<&String>, kernel: Option<&String>, pkgnames: &[String], force: bool,
+) -> Result<(), std::io::Error> {
+    log::warn!("Applying changes is deferred to \"{}\" trigger", pending::TRIGGER_NAME.bright_yellow());
+    let cfg_path = config::get().path;
+    pending::set_options(&pending::Options {
+        pm: Some(pm.name().to_string()),
+        root: root.cloned(),
+        kernel: kernel.cloned(),
+        config: if cfg_path.is_empty() { None } else { Some(cfg_path) },
+        force,
+    })?;
+    pending::add_unregister(pkgnames)?;
+    pending::set_commit()?;
+
+    if let Err(err) = pending::activate_trigger(debug) {
+        log::warn!("{}", err);
+        log::warn!("Pending changes can be applied by calling \"limopack --triggered\"");
+    }
+
+    Ok(())
+}
+
+/// Perform pending unregistrations and commits with the options of the deferred "--apply".
+/// This is meant to be called from dpkg trigger processing ("triggered" postinst), while
+/// dpkg is still running, so the work is then handed over to a detached process.
+pub fn do_process_pending(debug: &bool) -> Result<(), std::io::Error> {
+    if pending::get_unregister().is_empty() && !pending::is_commit() {
+        log::info!("No pending changes");
+        return Ok(());
+    }
+
+    if pakmod::dpkglock::in_transaction() {
+        return pending::spawn_detached(debug);
+    }
+
+    let opts = pending::get_options()?;
+    if let Some(cfg_path) = &opts.config {
+        config::load(Some(cfg_path))?;
+    }
+
+    // The package manager is waited for and then kept locked, until all pending changes are done,
+    // so packages, installed meanwhile, are taken into account
+    let root = pakmod::detect::get_root(opts.root.as_ref());
+    let pm = pakmod::detect::select(root, opts.pm.as_ref())?;
+    if pm == PkgManager::Dpkg {
+        log::info!("Waiting for dpkg to finish");
+    }
+    let _lock = pm.lock(debug, root, true)?;
+
+    // Changes could be recorded also meanwhile
+    let pkgnames = pending::get_unregister();
+    let commit = pending::is_commit();
+
+    // Unused modules are checked before any package is unregistered, so a refused commit leaves
+    // all pending changes as they are
+    let kinfos = if commit { get_kernels(debug, opts.kernel.as_ref())? } else { vec![] };
+    let unused = if commit { check_commit(debug, &kinfos, opts.force)? } else { vec![] };
+
+    if !pkgnames.is_empty() {
+        unregister_pkgs(debug, pm, root, &pkgnames)?;
+        pending::remove_unregister(&pkgnames)?;
+    }
+
+    if commit {
+        remove_unused(debug, &kinfos, unused)?;
+        pending::clear_commit()?;
+    }
+
+    pending::clear_options()
+}
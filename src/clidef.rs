@@ -19,9 +19,37 @@ pub fn cli(version: &'static str) -> Command {
             Arg::new("use")
                 .short('u')
                 .long("use")
-                .help("Specify comma-separated list of kernel modules to be used.\n")
+                .help(
+                    "Specify comma-separated list of kernel modules to be used.
+  Modules can be also selected by glob patterns with \"*\", \"**\", \"?\" and \"[...]\" wildcards
+  (e.g. \"kernel/drivers/net/wireless/**\" or \"snd-soc-*\") or by regular expressions
+  with \"re:\" prefix (e.g. \"re:^nf_\").\n",
+                )
                 .value_delimiter(','),
         )
+        .arg(Arg::new("profile").short('P').long("profile").value_delimiter(',').help(
+            "Specify comma-separated list of module profiles to be used, in addition
+  to the modules, specified with --use. Profiles are found in /etc/limopack/profiles.d\n",
+        ))
+        .arg(
+            Arg::new("store-pattern")
+                .long("store-pattern")
+                .requires("install")
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Register module patterns as they are, instead of modules they match,
+  so modules of kernels, installed later, also match them.",
+                ),
+        )
+        .arg(
+            Arg::new("allow-container")
+                .long("allow-container")
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Use loaded modules, when no modules are specified, or capture them with --capture
+  even in a container, where these are modules and devices of the host.",
+                ),
+        )
         .arg(
             Arg::new("static")
                 .short('s')
@@ -42,10 +70,86 @@ pub fn cli(version: &'static str) -> Command {
   be used. This includes all dependencies and already marked
   and existing modules.",
         ))
+        .arg(
+            Arg::new("initramfs")
+                .long("initramfs")
+                .requires("list")
+                .action(ArgAction::SetTrue)
+                .help("Also list modules, required by the initramfs configuration, and their dependencies."),
+        )
+        .arg(
+            Arg::new("cost")
+                .long("cost")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all([
+                    "use",
+                    "profile",
+                    "static",
+                    "tree",
+                    "list",
+                    "install",
+                    "remove",
+                    "exclude",
+                    "global",
+                    "import-autoload",
+                    "capture",
+                    "import-capture",
+                    "apply",
+                    "pkname",
+                    "triggered",
+                    "reregister",
+                    "kernel-postinst",
+                    "force",
+                ])
+                .help(
+                    "Display cost of each registered module: size of its dependency closure and
+  size of modules, which only it keeps (i.e. freed once it is removed), most expensive first.",
+                ),
+        )
+        .arg(
+            Arg::new("status")
+                .long("status")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all([
+                    "use",
+                    "profile",
+                    "static",
+                    "tree",
+                    "list",
+                    "cost",
+                    "kernel",
+                    "install",
+                    "remove",
+                    "exclude",
+                    "global",
+                    "import-autoload",
+                    "capture",
+                    "import-capture",
+                    "apply",
+                    "pkname",
+                    "triggered",
+                    "reregister",
+                    "kernel-postinst",
+                    "force",
+                ])
+                .help(
+                    "Compare loaded modules with registered modules of the running kernel and their dependencies.
+  Fails, if any loaded module is not registered, as it would be removed on the next --apply.",
+                ),
+        )
         .arg(Arg::new("pkname").short('p').long("pkname").value_delimiter(',').help(
             "Specify a package name, which needs to be un-registered
   from the package manager database in order to be visible to the system as
-  non-existing, so the system can bring it again for an update or installation.\n",
+  non-existing, so the system can bring it again for an update or installation.
+  Accepts comma-separated list of packages, also as wildcard patterns.\n",
+        ))
+        .arg(Arg::new("pm").long("pm").value_parser(["dpkg", "opkg", "apk", "pacman"]).help(
+            "Specify a package manager, which database should be altered.
+  By default it is detected from the databases found on the system.\n",
+        ))
+        .arg(Arg::new("root").long("root").value_name("dir").help(
+            "Specify a root directory of the system, which package manager database should be altered
+  (e.g. of an image being provisioned). By default it is \"/\".\n",
         ))
         // Writable
         .arg(
@@ -68,11 +172,83 @@ pub fn cli(version: &'static str) -> Command {
   the modules to be removed, but does not actually removes them.",
                 ),
         )
+        .arg(
+            Arg::new("exclude")
+                .short('x')
+                .long("exclude")
+                .conflicts_with_all(["install", "remove", "static"])
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Exclude specified modules from being used by a kernel, even if they are
+  registered globally. Removing them afterwards drops the exclusion.",
+                ),
+        )
+        .arg(
+            Arg::new("global")
+                .short('g')
+                .long("global")
+                .conflicts_with_all(["kernel", "exclude"])
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Mark or remove specified modules system-wide in /etc/limopack/modules.active,
+  so they are used by every kernel, including those installed later.",
+                ),
+        )
+        .arg(
+            Arg::new("import-autoload")
+                .long("import-autoload")
+                .conflicts_with_all(["use", "profile", "static", "tree", "list", "install", "remove", "exclude"])
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Register modules, those are loaded at boot (/etc/modules, modules-load.d directories
+  and \"modules-load=\" or \"rd.driver.pre=\" of the kernel command line), as permanent ones.
+  Use --global to register them system-wide.",
+                ),
+        )
+        .arg(
+            Arg::new("capture")
+                .long("capture")
+                .value_name("file")
+                .conflicts_with_all([
+                    "use", "profile", "static", "tree", "list", "install", "remove", "exclude", "import-autoload",
+                ])
+                .help(
+                    "Capture loaded modules, modaliases of devices, kernel version and command line
+  of this (reference) system to a file, so it can be imported into another image.\n",
+                ),
+        )
+        .arg(
+            Arg::new("import-capture")
+                .long("import-capture")
+                .value_name("file")
+                .conflicts_with_all([
+                    "use", "profile", "static", "tree", "list", "install", "remove", "exclude", "import-autoload", "capture",
+                ])
+                .help(
+                    "Register modules of a capture, taken with --capture on a reference system,
+  as static ones. Modules are mapped by their names to the target kernels.
+  Use --global to register them system-wide.\n",
+                ),
+        )
+        .arg(
+            Arg::new("modaliases")
+                .long("modaliases")
+                .requires("import-capture")
+                .action(ArgAction::SetTrue)
+                .help("Also register modules, matching captured modaliases in modules.alias of the target kernels."),
+        )
+        .arg(Arg::new("kernel").short('k').long("kernel").help(
+            "Specify a kernel version to operate on, or \"running\" or \"latest\" kernel.
+  By default all kernels found in the system are affected.\n",
+        ))
         .arg(
             Arg::new("apply")
                 .short('a')
                 .long("apply")
-                .conflicts_with_all(["use", "static", "tree", "list", "install", "remove"])
+                .conflicts_with_all([
+                    "use", "profile", "static", "tree", "list", "install", "remove", "exclude", "import-autoload", "capture",
+                    "import-capture",
+                ])
                 .requires("pkname")
                 .action(ArgAction::SetTrue)
                 .help(format!(
@@ -82,7 +258,96 @@ pub fn cli(version: &'static str) -> Command {
                     "  NOTE: this option can be only used alone, as it commits the changes.\n".yellow()
                 )),
         )
+        .arg(
+            Arg::new("defer")
+                .long("defer")
+                .requires("apply")
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Do not apply the changes right away, but record them as pending
+  and activate \"limopack-apply\" dpkg trigger, which will apply them later.
+  This is implied, if called within a running dpkg transaction.",
+                ),
+        )
+        .arg(
+            Arg::new("triggered")
+                .long("triggered")
+                .conflicts_with_all([
+                    "use", "profile", "static", "tree", "list", "install", "remove", "exclude", "apply", "pkname", "kernel", "pm",
+                    "root", "force",
+                ])
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Apply pending changes. This is meant to be called from the \"triggered\"
+  postinst of a package, interested in \"limopack-apply\" dpkg trigger. Changes are applied
+  with the options of the deferred --apply (package manager, root, kernels and forcing).",
+                ),
+        )
+        .arg(
+            Arg::new("reregister")
+                .long("reregister")
+                .conflicts_with_all([
+                    "use", "profile", "static", "tree", "list", "install", "remove", "exclude", "apply", "pkname",
+                ])
+                .help(
+                    "Register back a package, previously un-registered with --pkname,
+  so the package manager owns its content again (e.g. before a distribution upgrade).
+  The package is restored from the latest backup, containing it.\n",
+                ),
+        )
+        .arg(
+            Arg::new("kernel-postinst")
+                .long("kernel-postinst")
+                .num_args(1..=2)
+                .value_names(["version", "image"])
+                .conflicts_with_all([
+                    "use",
+                    "profile",
+                    "static",
+                    "tree",
+                    "list",
+                    "install",
+                    "remove",
+                    "exclude",
+                    "apply",
+                    "pkname",
+                    "triggered",
+                    "reregister",
+                ])
+                .help(
+                    "Carry registered modules over to a newly installed kernel of the specified version.
+  This is meant to be called from /etc/kernel/postinst.d hook, so the kernel image path
+  can be passed as well, which is ignored.\n",
+                ),
+        )
+        .arg(Arg::new("from").long("from").requires("kernel-postinst").help(
+            "Specify a kernel version, which registered modules should be carried over.
+  By default it is the latest other kernel, which has registered modules.\n",
+        ))
+        .arg(
+            Arg::new("prune")
+                .long("prune")
+                .requires("kernel-postinst")
+                .action(ArgAction::SetTrue)
+                .help("Remove unused modules of the new kernel right away, once modules are carried over."),
+        )
+        .arg(
+            Arg::new("force")
+                .long("force")
+                .conflicts_with_all(["use", "profile", "static", "tree", "list", "install", "remove", "exclude", "reregister"])
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Remove unused modules, even if they are protected (e.g. storage and filesystem drivers),
+  or configured to be loaded at boot, or loaded in the running kernel, or needed to mount
+  its root and boot filesystems.
+  Applies to --apply, also when it is deferred, and --prune.\n",
+                ),
+        )
         // Other
+        .arg(Arg::new("config").short('c').long("config").help(
+            "Specify a configuration file with defaults and policies.
+  By default it is /etc/limopack.conf, if it exists.\n",
+        ))
         .arg(
             Arg::new("debug")
                 .short('d')
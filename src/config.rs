@@ -0,0 +1,230 @@
+//! Configuration of defaults and policies, which is read from /etc/limopack.conf
+//! or from a file, specified with "--config" option. Options, specified in the
+//! command line, take precedence over the configuration.
+//!
+//! Format is "<key> = <value>" per a line, lists are comma-separated. Example:
+//!
+//!     # Kernel modules location
+//!     modules_root = /lib/modules
+//!
+//!     # Package manager, which database is altered, and the root directory of the system,
+//!     # where its database is, e.g. of an image being provisioned
+//!     package_manager = dpkg
+//!     root = /
+//!
+//!     # Kernels to operate on: all, running, latest or a specific version
+//!     kernel = running
+//!
+//!     # Modules, those are always kept or never kept, regardless of being registered.
+//!     # Modules to keep take precedence, and dependencies of kept modules are never removed.
+//!     always_keep = ext4, nvme
+//!     never_keep = floppy
+//!
+//!     # Modules, which are never removed, unless forced. These are added to the built-in
+//!     # patterns of storage and filesystem drivers, which can be also turned off.
+//!     protected = kernel/drivers/mmc/**, kernel/drivers/net/ethernet/intel/e1000e/**
+//!     protected_defaults = yes
+//!
+//!     # Move unused modules to a directory, instead of deleting them
+//!     quarantine = /var/lib/limopack/quarantine
+//!
+//!     # Logging: error, warn, info or debug; also to a file
+//!     log_level = info
+//!     log_file = /var/log/limopack.log
+
+use std::{
+    fs,
+    io::{Error, ErrorKind},
+    path::Path,
+    sync::RwLock,
+};
+
+pub static CONFIG_F: &str = "/etc/limopack.conf";
+
+/// Built-in patterns of modules, those are usually needed to boot: storage and filesystem drivers
+pub static PROTECTED: [&str; 14] = [
+    "kernel/fs/ext4/**",
+    "kernel/fs/jbd2/**",
+    "kernel/fs/xfs/**",
+    "kernel/fs/btrfs/**",
+    "kernel/fs/fat/**",
+    "kernel/fs/squashfs/**",
+    "kernel/fs/overlayfs/**",
+    "kernel/drivers/nvme/**",
+    "kernel/drivers/ata/**",
+    "kernel/drivers/md/**",
+    "kernel/drivers/scsi/sd_mod",
+    "kernel/drivers/scsi/virtio_scsi",
+    "kernel/drivers/block/virtio_blk",
+    "kernel/drivers/virtio/**",
+];
+
+static CONFIG: RwLock<Config> = RwLock::new(Config::new());
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub path: String,
+    pub modules_root: Option<String>,
+    pub package_manager: Option<String>,
+    pub root: Option<String>,
+    pub kernel: Option<String>,
+    pub always_keep: Vec<String>,
+    pub never_keep: Vec<String>,
+    pub protected: Vec<String>,
+    pub protected_defaults: bool,
+    pub quarantine: Option<String>,
+    pub log_level: Option<log::LevelFilter>,
+    pub log_file: Option<String>,
+}
+
+impl Config {
+    const fn new() -> Self {
+        Config {
+            path: String::new(),
+            modules_root: None,
+            package_manager: None,
+            root: None,
+            kernel: None,
+            always_keep: Vec::new(),
+            never_keep: Vec::new(),
+            protected: Vec::new(),
+            protected_defaults: true,
+            quarantine: None,
+            log_level: None,
+            log_file: None,
+        }
+    }
+
+    /// Parse configuration data
+    fn parse(path: &str, data: &str) -> Result<Self, Error> {
+        let mut cfg = Config::new();
+        cfg.path = path.to_string();
+
+        for (idx, line) in data.lines().map(|l| l.trim()).enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let err = |msg: &str| Error::new(ErrorKind::InvalidData, format!("{} in {} at line {}", msg, path, idx + 1));
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim().to_string()),
+                None => return Err(err(&format!("Unsupported entry \"{}\"", line))),
+            };
+
+            if value.is_empty() {
+                continue;
+            }
+
+            let list: Vec<String> = value.split(',').map(|v| v.trim().to_string()).filter(|v| !v.is_empty()).collect();
+            match key {
+                "modules_root" => cfg.modules_root = Some(value),
+                "package_manager" => cfg.package_manager = Some(value),
+                "root" => cfg.root = Some(value),
+                "kernel" => cfg.kernel = Some(value),
+                "always_keep" => cfg.always_keep.extend(list),
+                "never_keep" => cfg.never_keep.extend(list),
+                "protected" => cfg.protected.extend(list),
+                "protected_defaults" => match value.to_lowercase().as_str() {
+                    "yes" | "true" | "1" => cfg.protected_defaults = true,
+                    "no" | "false" | "0" => cfg.protected_defaults = false,
+                    _ => return Err(err(&format!("Unsupported value \"{}\" of \"{}\"", value, key))),
+                },
+                "quarantine" => cfg.quarantine = Some(value),
+                "log_level" => match value.parse::<log::LevelFilter>() {
+                    Ok(level) => cfg.log_level = Some(level),
+                    Err(_) => return Err(err(&format!("Unsupported log level \"{}\"", value))),
+                },
+                "log_file" => cfg.log_file = Some(value),
+                _ => return Err(err(&format!("Unknown option \"{}\"", key))),
+            }
+        }
+
+        Ok(cfg)
+    }
+
+    /// Get patterns of protected modules: built-in ones, unless turned off, and configured ones
+    pub fn get_protected(&self) -> Vec<String> {
+        let mut out: Vec<String> = vec![];
+        if self.protected_defaults {
+            out.extend(PROTECTED.iter().map(|p| p.to_string()));
+        }
+        out.extend(self.protected.to_owned());
+
+        out
+    }
+}
+
+/// Load configuration from the specified file, otherwise from the default one,
+/// which is not required to exist.
+pub fn load(path: Option<&String>) -> Result<(), Error> {
+    let cfg_path = path.map(|p| p.as_str()).unwrap_or(CONFIG_F);
+    if path.is_none() && !Path::new(cfg_path).exists() {
+        return Ok(());
+    }
+
+    let cfg = match fs::read_to_string(cfg_path) {
+        Ok(data) => Config::parse(cfg_path, &data)?,
+        Err(err) => return Err(Error::new(err.kind(), format!("Unable to read configuration {}: {}", cfg_path, err))),
+    };
+
+    *CONFIG.write().unwrap() = cfg;
+    Ok(())
+}
+
+/// Get current configuration
+pub fn get() -> Config {
+    CONFIG.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, PROTECTED};
+    use std::io::ErrorKind;
+
+    #[test]
+    fn all_options_are_parsed() {
+        let data = "# Comment\n\nmodules_root = /mnt/lib/modules\npackage_manager = pacman\nroot = /mnt\nkernel = latest\n\
+                    always_keep = ext4, nvme,\nalways_keep = xfs\nnever_keep = floppy\nprotected = kernel/drivers/mmc/**\n\
+                    protected_defaults = No\nquarantine = /var/lib/limopack/quarantine\nlog_level = debug\n\
+                    log_file = /var/log/limopack.log\nkernel =\n";
+        let cfg = Config::parse("limopack.conf", data).unwrap();
+
+        assert_eq!(cfg.path, "limopack.conf");
+        assert_eq!(cfg.modules_root.as_deref(), Some("/mnt/lib/modules"));
+        assert_eq!(cfg.package_manager.as_deref(), Some("pacman"));
+        assert_eq!(cfg.root.as_deref(), Some("/mnt"));
+        assert_eq!(cfg.kernel.as_deref(), Some("latest"));
+        assert_eq!(cfg.always_keep, ["ext4", "nvme", "xfs"]);
+        assert_eq!(cfg.never_keep, ["floppy"]);
+        assert!(!cfg.protected_defaults);
+        assert_eq!(cfg.get_protected(), ["kernel/drivers/mmc/**"]);
+        assert_eq!(cfg.quarantine.as_deref(), Some("/var/lib/limopack/quarantine"));
+        assert_eq!(cfg.log_level, Some(log::LevelFilter::Debug));
+        assert_eq!(cfg.log_file.as_deref(), Some("/var/log/limopack.log"));
+    }
+
+    #[test]
+    fn defaults_are_kept() {
+        let cfg = Config::parse("limopack.conf", "").unwrap();
+        assert!(cfg.modules_root.is_none() && cfg.kernel.is_none() && cfg.always_keep.is_empty());
+        assert!(cfg.protected_defaults);
+
+        let cfg = Config::parse("limopack.conf", "protected = e1000e\n").unwrap();
+        assert_eq!(cfg.get_protected().len(), PROTECTED.len() + 1);
+        assert_eq!(cfg.get_protected().last().map(|p| p.as_str()), Some("e1000e"));
+    }
+
+    #[test]
+    fn invalid_entries_are_refused() {
+        for (data, msg) in [
+            ("kernel = all\nmodules\n", "Unsupported entry \"modules\" in limopack.conf at line 2"),
+            ("# Comment\nkernels = all\n", "Unknown option \"kernels\" in limopack.conf at line 2"),
+            ("protected_defaults = maybe\n", "Unsupported value \"maybe\" of \"protected_defaults\" in limopack.conf at line 1"),
+            ("log_level = loud\n", "Unsupported log level \"loud\" in limopack.conf at line 1"),
+        ] {
+            let err = Config::parse("limopack.conf", data).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert_eq!(err.to_string(), msg);
+        }
+    }
+}
@@ -1,6 +1,40 @@
 use chrono::Local;
 use colored::{self, Colorize};
 use log::{Level, Metadata, Record};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::Mutex,
+};
+
+// Optional log file, where messages are also written to
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Also write log messages to a file, appending them
+pub(crate) fn set_log_file(path: &str) -> Result<(), std::io::Error> {
+    *LOG_FILE.lock().unwrap() = Some(OpenOptions::new().create(true).append(true).open(path)?);
+    Ok(())
+}
+
+/// Remove terminal colors from a message
+fn strip_colors(msg: &str) -> String {
+    let mut out = String::new();
+    let mut chars = msg.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip "ESC[...m" sequence
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+
+    out
+}
 
 pub(crate) struct STDOUTLogger;
 
@@ -19,7 +53,12 @@ impl log::Log for STDOUTLogger {
                 log::Level::Trace => format!("{}", msg.level().as_str().cyan()),
             };
 
-            println!("[{}] - {}: {}", Local::now().format("%d/%m/%Y %H:%M:%S"), s_level, msg.args());
+            let now = Local::now().format("%d/%m/%Y %H:%M:%S");
+            println!("[{}] - {}: {}", now, s_level, msg.args());
+
+            if let Some(f_ptr) = LOG_FILE.lock().unwrap().as_mut() {
+                let _ = writeln!(f_ptr, "[{}] - {}: {}", now, msg.level().as_str(), strip_colors(&msg.args().to_string()));
+            }
         }
     }
 
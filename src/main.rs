@@ -1,9 +1,12 @@
 mod actions;
 mod clidef;
+mod config;
 mod logger;
 mod mdb;
 mod mtree;
 mod pakmod;
+mod pending;
+mod syscfg;
 mod sysutils;
 
 use clap::Error;
@@ -13,8 +16,9 @@ static VERSION: &str = "0.1";
 static LOGGER: logger::STDOUTLogger = logger::STDOUTLogger;
 
 /// Initialise logger etc
-fn init(debug: &bool) -> Result<(), log::SetLoggerError> {
-    log::set_logger(&LOGGER).map(|()| log::set_max_level(if *debug { log::LevelFilter::Trace } else { log::LevelFilter::Info }))
+fn init(debug: &bool, level: Option<log::LevelFilter>) -> Result<(), log::SetLoggerError> {
+    log::set_logger(&LOGGER)
+        .map(|()| log::set_max_level(if *debug { log::LevelFilter::Trace } else { level.unwrap_or(log::LevelFilter::Info) }))
 }
 
 fn if_err(res: Result<(), std::io::Error>) {
@@ -37,22 +41,50 @@ fn main() -> Result<(), Error> {
     }
 
     let params = cli.to_owned().get_matches();
-    let debug: bool = params.get_flag("debug");
+    let cfg_res = config::load(params.get_one::<String>("config"));
+    let cfg = config::get();
+    let debug: bool = params.get_flag("debug") || cfg.log_level >= Some(log::LevelFilter::Debug);
 
-    init(&debug).unwrap();
+    init(&debug, cfg.log_level).unwrap();
+    if_err(cfg_res);
+    if let Some(log_file) = &cfg.log_file {
+        if_err(logger::set_log_file(log_file));
+    }
+
+    // Options, specified in the command line take precedence over the configuration
+    let pm = params.get_one::<String>("pm").or(cfg.package_manager.as_ref());
+    let root = params.get_one::<String>("root").or(cfg.root.as_ref());
+    let root_path = pakmod::detect::get_root(root);
+    let kernel = params.get_one::<String>("kernel").or(cfg.kernel.as_ref());
 
     // Check if user has required access
-    if params.get_flag("install") || params.get_flag("remove") || params.get_flag("apply") {
+    if params.get_flag("install")
+        || params.get_flag("remove")
+        || params.get_flag("exclude")
+        || params.get_flag("import-autoload")
+        || params.get_one::<String>("import-capture").is_some()
+        || params.get_flag("apply")
+        || params.get_flag("triggered")
+        || params.get_one::<String>("reregister").is_some()
+        || params.get_one::<String>("kernel-postinst").is_some()
+    {
         if_err(sysutils::user_is_root());
     }
 
     let modlist = params.get_one::<String>("use");
-    let modules: Vec<String> = if modlist.is_some() {
+    let mut modules: Vec<String> = if modlist.is_some() {
         params.get_many::<String>("use").unwrap().collect::<Vec<_>>().iter().map(|x| x.to_string()).collect()
     } else {
         vec![]
     };
 
+    if let Some(profiles) = params.get_many::<String>("profile") {
+        match mdb::profiles::get_modules(&profiles.map(|x| x.to_string()).collect::<Vec<String>>(), &debug) {
+            Ok(pmods) => modules.extend(pmods.into_iter().filter(|m| !modules.contains(m)).collect::<Vec<String>>()),
+            Err(err) => if_err(Err(err)),
+        }
+    }
+
     // If modules are not specified (or magic keyword?), then all are static,
     // because they are currently loaded and in use.
     let is_static = if modules.is_empty() { true } else { params.get_flag("static") };
@@ -60,26 +92,75 @@ fn main() -> Result<(), Error> {
     if params.get_flag("version") {
         println!("Version: {}", VERSION);
     } else if params.get_flag("tree") {
-        actions::do_tree(&debug, &modules);
+        if_err(actions::do_tree(&debug, kernel, &modules));
     } else if params.get_flag("list") {
-        for modname in actions::do_list(&debug, &modules) {
-            println!("{}", modname);
+        match actions::do_list(&debug, kernel, &modules, params.get_flag("initramfs")) {
+            Ok(modnames) => {
+                for modname in modnames {
+                    println!("{}", modname);
+                }
+            }
+            Err(err) => if_err(Err(err)),
         }
+    } else if params.get_flag("cost") {
+        if_err(actions::do_cost(&debug, kernel));
+    } else if params.get_flag("status") {
+        if_err(actions::do_status(&debug));
     } else if params.get_flag("install") {
-        if_err(actions::do_add(&debug, is_static, &modules));
+        if_err(actions::do_add(
+            &debug,
+            kernel,
+            params.get_flag("global"),
+            is_static,
+            params.get_flag("store-pattern"),
+            params.get_flag("allow-container"),
+            &modules,
+        ));
     } else if params.get_flag("remove") {
-        if_err(actions::do_remove(&debug, &modules));
+        if_err(actions::do_remove(&debug, kernel, params.get_flag("global"), params.get_flag("allow-container"), &modules));
+    } else if params.get_flag("exclude") {
+        if modules.is_empty() {
+            if_err(Err(std::io::Error::new(ErrorKind::InvalidInput, "Modules to exclude are not specified")))
+        }
+        if_err(actions::do_exclude(&debug, kernel, &modules));
+    } else if let Some(path) = params.get_one::<String>("capture") {
+        if_err(actions::do_capture(path, params.get_flag("allow-container")));
+    } else if let Some(path) = params.get_one::<String>("import-capture") {
+        if_err(actions::do_import_capture(&debug, kernel, params.get_flag("global"), path, params.get_flag("modaliases")));
+    } else if params.get_flag("import-autoload") {
+        if_err(actions::do_import_autoload(&debug, kernel, params.get_flag("global")));
     } else if params.get_flag("apply") {
-        match params.get_one::<String>("pkname") {
-            Some(pkname) => {
-                if pkname.is_empty() {
+        match params.get_many::<String>("pkname") {
+            Some(pknames) => {
+                let pknames: Vec<String> = pknames.filter(|x| !x.is_empty()).map(|x| x.to_string()).collect();
+                if pknames.is_empty() {
                     if_err(Err(std::io::Error::new(ErrorKind::InvalidInput, "Package name is not specified")))
                 }
-                if_err(actions::do_unregister_pkg(&debug, pkname));
-                if_err(actions::do_commit(&debug))
+                match pakmod::detect::select(root_path, pm) {
+                    Ok(pm) if params.get_flag("defer") || actions::must_defer(pm) => {
+                        if_err(actions::do_defer(&debug, pm, root, kernel, &pknames, params.get_flag("force")))
+                    }
+                    Ok(pm) => if_err(actions::do_apply(&debug, pm, root_path, kernel, &pknames, params.get_flag("force"))),
+                    Err(err) => if_err(Err(err)),
+                }
             }
             None => todo!(),
         }
+    } else if params.get_flag("triggered") {
+        if_err(actions::do_process_pending(&debug));
+    } else if let Some(pkname) = params.get_one::<String>("reregister") {
+        match pakmod::detect::select(root_path, pm) {
+            Ok(pm) => if_err(actions::do_reregister_pkg(&debug, pm, root_path, pkname)),
+            Err(err) => if_err(Err(err)),
+        }
+    } else if let Some(kver) = params.get_one::<String>("kernel-postinst") {
+        if_err(actions::do_kernel_postinst(
+            &debug,
+            kver,
+            params.get_one::<String>("from"),
+            params.get_flag("prune"),
+            params.get_flag("force"),
+        ));
     } else {
         cli.print_help().unwrap();
     }
@@ -0,0 +1,218 @@
+//! Capture of a reference machine: modules, loaded on it, modaliases of its devices,
+//! its kernel version and its command line. A capture is taken on a live system and
+//! is imported into another image, so vital modules can be determined offline.
+//!
+//! Format is "<key> = <value>" per a line, "module" and "modalias" keys are repeated.
+//! Example:
+//!
+//!     kernel = 6.1.0-13-amd64
+//!     cmdline = BOOT_IMAGE=/boot/vmlinuz-6.1.0-13-amd64 root=/dev/vda1 ro quiet
+//!     module = virtio_net
+//!     modalias = virtio:d00000001v00001AF4
+
+use crate::mtree::kerman::kman::{self, KernelInfo};
+use crate::sysutils;
+use chrono::Local;
+use regex::Regex;
+use std::{
+    fs,
+    io::{Error, ErrorKind},
+    path::Path,
+};
+use walkdir::WalkDir;
+
+use super::modules::modinfo;
+
+static PROC_MODULES: &str = "/proc/modules";
+static PROC_CMDLINE: &str = "/proc/cmdline";
+static SYS_DEVICES: &str = "/sys/devices";
+static MOD_ALIAS: &str = "modules.alias";
+
+#[derive(Debug, Default)]
+pub struct Capture {
+    pub kernel: String,
+    pub cmdline: String,
+    pub modules: Vec<String>,
+    pub modaliases: Vec<String>,
+}
+
+impl Capture {
+    /// Take a capture of the running system
+    pub fn take() -> Result<Self, Error> {
+        // lsmod exits, if there are no loaded modules information at all
+        if !Path::new(PROC_MODULES).exists() {
+            return Err(Error::new(ErrorKind::NotFound, format!("Unable to get loaded modules: {} is not found", PROC_MODULES)));
+        }
+
+        let mut modules: Vec<String> = modinfo::lsmod().into_iter().map(|m| m.name).collect();
+        modules.sort();
+
+        let mut modaliases: Vec<String> = vec![];
+        for e in WalkDir::new(SYS_DEVICES).into_iter().flatten() {
+            if e.file_type().is_file() && e.file_name() == "modalias" {
+                if let Ok(alias) = fs::read_to_string(e.path()) {
+                    let alias = alias.trim().to_string();
+                    if !alias.is_empty() && !modaliases.contains(&alias) {
+                        modaliases.push(alias);
+                    }
+                }
+            }
+        }
+        modaliases.sort();
+
+        Ok(Capture {
+            kernel: sysutils::get_running_kernel()?,
+            cmdline: fs::read_to_string(PROC_CMDLINE).unwrap_or_default().trim().to_string(),
+            modules,
+            modaliases,
+        })
+    }
+
+    /// Load a capture from a file
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(err) => return Err(Error::new(err.kind(), format!("Unable to read capture {}: {}", path, err))),
+        };
+
+        let mut cap = Capture::default();
+        for (idx, line) in data.lines().map(|l| l.trim()).enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim().to_string()),
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Unsupported entry \"{}\" in {} at line {}", line, path, idx + 1),
+                    ))
+                }
+            };
+
+            match key {
+                "kernel" => cap.kernel = value,
+                "cmdline" => cap.cmdline = value,
+                "module" => cap.modules.push(value),
+                "modalias" => cap.modaliases.push(value),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Unknown key \"{}\" in {} at line {}", key, path, idx + 1),
+                    ))
+                }
+            }
+        }
+
+        Ok(cap)
+    }
+
+    /// Save the capture to a file
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        let mut data = format!("# Captured by limopack on {}\n", Local::now().format("%d/%m/%Y %H:%M:%S"));
+        data.push_str(&format!("kernel = {}\ncmdline = {}\n", self.kernel, self.cmdline));
+        for m in &self.modules {
+            data.push_str(&format!("module = {}\n", m));
+        }
+        for a in &self.modaliases {
+            data.push_str(&format!("modalias = {}\n", a));
+        }
+
+        fs::write(path, data)
+    }
+}
+
+/// Convert a glob of modules.alias to a regular expression
+fn alias_to_regex(alias: &str) -> Result<Regex, Error> {
+    Regex::new(&format!("^{}$", sysutils::glob_to_regex(alias)))
+        .map_err(|err| Error::new(ErrorKind::InvalidData, format!("Invalid module alias \"{}\": {}", alias, err)))
+}
+
+/// Resolve modaliases to names of modules of a kernel, using its modules.alias
+pub fn resolve_modaliases(ki: &KernelInfo, modaliases: &[String]) -> Result<Vec<String>, Error> {
+    let path = ki.get_kernel_path().join(MOD_ALIAS);
+    let data = match fs::read_to_string(&path) {
+        Ok(data) => data,
+        Err(err) => return Err(Error::new(err.kind(), format!("Unable to read {}: {}", path.to_str().unwrap(), err))),
+    };
+
+    let mut out: Vec<String> = vec![];
+    for line in data.lines() {
+        let (alias, name) = match line.split_whitespace().collect::<Vec<&str>>().as_slice() {
+            ["alias", alias, name] => (alias.to_string(), kman::get_module_name(name)),
+            _ => continue,
+        };
+
+        // Most of aliases start with a literal prefix, so there is no need to match them all
+        let prefix = alias.split(['*', '?', '[']).next().unwrap_or_default();
+        if out.contains(&name) || !modaliases.iter().any(|a| a.starts_with(prefix)) {
+            continue;
+        }
+
+        let re = alias_to_regex(&alias)?;
+        if modaliases.iter().any(|a| re.is_match(a)) {
+            out.push(name);
+        }
+    }
+    out.sort();
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{alias_to_regex, Capture};
+    use std::{env, fs, io::ErrorKind, process};
+
+    fn tmp_path(name: &str) -> String {
+        env::temp_dir().join(format!("limopack-test-{}-{}", process::id(), name)).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn capture_round_trip() {
+        let path = tmp_path("capture");
+        let cap = Capture {
+            kernel: "6.1.0-13-amd64".to_string(),
+            cmdline: "BOOT_IMAGE=/boot/vmlinuz-6.1.0-13-amd64 root=/dev/vda1 ro quiet".to_string(),
+            modules: vec!["virtio_blk".to_string(), "virtio_net".to_string()],
+            modaliases: vec!["virtio:d00000001v00001AF4".to_string()],
+        };
+        cap.save(&path).unwrap();
+        let loaded = Capture::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.kernel, cap.kernel);
+        assert_eq!(loaded.cmdline, cap.cmdline);
+        assert_eq!(loaded.modules, cap.modules);
+        assert_eq!(loaded.modaliases, cap.modaliases);
+    }
+
+    #[test]
+    fn invalid_entries_are_refused() {
+        let path = tmp_path("capture-invalid");
+        for (data, msg) in [
+            ("kernel = 6.1.0\nvirtio_net\n", format!("Unsupported entry \"virtio_net\" in {} at line 2", path)),
+            ("# Comment\n\nmodules = virtio_net\n", format!("Unknown key \"modules\" in {} at line 3", path)),
+        ] {
+            fs::write(&path, data).unwrap();
+            let err = Capture::load(&path).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert_eq!(err.to_string(), msg);
+        }
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(Capture::load(&path).unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn aliases_match_modaliases() {
+        let re = alias_to_regex("pci:v00008086d000015B8sv*sd*bc*sc*i*").unwrap();
+        assert!(re.is_match("pci:v00008086d000015B8sv00001028sd000007A1bc02sc00i00"));
+        assert!(!re.is_match("pci:v00008086d000015B7sv00001028sd000007A1bc02sc00i00"));
+
+        let re = alias_to_regex("usb:v*p*d*dc*dsc*dp*ic08isc06ip50in*").unwrap();
+        assert!(re.is_match("usb:v0781p5581d0100dc00dsc00dp00ic08isc06ip50in00"));
+        assert!(alias_to_regex("virtio:d00000001v*").unwrap().is_match("virtio:d00000001v00001AF4"));
+    }
+}
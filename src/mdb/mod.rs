@@ -1,2 +1,4 @@
+pub mod capture;
 pub mod modlist;
 pub mod modules;
+pub mod profiles;
@@ -13,6 +13,40 @@ use std::{
 };
 use walkdir::WalkDir;
 
+static MOD_STOR: &str = "modules.active";
+static GLOBAL_STOR: &str = "/etc/limopack/modules.active";
+
+// Marker of a module, which is excluded for a specific kernel
+static EXCLUDED: i16 = -2;
+
+/// Marker of a module, imported from the configuration of modules, loaded at boot.
+/// Such module is kept permanently, as a static one.
+pub static AUTOLOAD: i16 = -3;
+
+/// Get storage path of the module tracker for a specific kernel
+fn get_storage_path(kinfo: &KernelInfo) -> PathBuf {
+    kman::get_modules_root().join(&kinfo.version).join(MOD_STOR)
+}
+
+/// Returns true if a specific kernel has modules tracked
+pub fn has_storage(kinfo: &KernelInfo) -> bool {
+    get_storage_path(kinfo).exists()
+}
+
+/// Move a file, also across filesystems
+fn move_file(src: &Path, dst: &Path) -> Result<(), std::io::Error> {
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if fs::rename(src, dst).is_err() {
+        fs::copy(src, dst)?;
+        fs::remove_file(src)?;
+    }
+
+    Ok(())
+}
+
 /// Module tracker
 /// Used modules are stored a plain-text file in /lib/modules/<version>/modules.active
 /// and each module is tracked in a garbage-collector style: if there is a software
@@ -37,30 +71,36 @@ use walkdir::WalkDir;
 ///
 ///     <int> - Number of references (software components) that require that module
 ///     S     - Static permanent module
+///     X     - Module is excluded for this kernel, even if it is in the global list
+///     A     - Permanent module, imported from the configuration of modules, loaded at boot
 ///
 /// Example:
 ///
 ///     kernel/drivers/net/tap.ko:S
 ///     kernel/drivers/acpi/acpi_pad.ko:1
 ///
-
-static MOD_STOR: &str = "modules.active";
-
+/// Optionally, modules can be tracked system-wide in /etc/limopack/modules.active,
+/// regardless of the kernel version. The format is the same, except modules are
+/// keyed only by their names (e.g. "acpi_pad"), as paths may differ per a kernel.
+/// Such modules are kept in every kernel, unless it excludes them.
 pub struct ModList<'a> {
     // Map to path to a module which referring to a number.
     // The number is referring to negative, zero and positive values:
     //   - negative value (-1) is "S" (static module)
+    //   - negative value (-2) is "X" (excluded module)
+    //   - negative value (-3) is "A" (module, loaded at boot)
     //   - zero value makes a module to be a subject for garbage collection
     //   - any positive value is a counter for the references
     modlist: HashMap<String, i16>,
-    kinfo: &'a KernelInfo<'a>,
+    storage: PathBuf,
+    kinfo: Option<&'a KernelInfo<'a>>,
     debug: &'a bool,
 }
 
 impl<'a> ModList<'a> {
     /// Constructor
     pub fn new(kinfo: &'a KernelInfo, debug: &'a bool) -> Result<Self, std::io::Error> {
-        let mut modlist = ModList { modlist: HashMap::default(), kinfo, debug };
+        let mut modlist = ModList { modlist: HashMap::default(), storage: get_storage_path(kinfo), kinfo: Some(kinfo), debug };
 
         let loaded = modlist.load();
         if loaded.is_err() {
@@ -70,16 +110,36 @@ impl<'a> ModList<'a> {
         }
     }
 
+    /// Constructor of the system-wide module list, which is not bound to any kernel
+    pub fn global(debug: &'a bool) -> Result<Self, std::io::Error> {
+        let mut modlist = ModList { modlist: HashMap::default(), storage: PathBuf::from(GLOBAL_STOR), kinfo: None, debug };
+        modlist.load()?;
+
+        Ok(modlist)
+    }
+
     // Get storage path
     fn get_storage_path(&self) -> PathBuf {
-        Path::new(kman::MOD_D).join(&self.kinfo.version).join(MOD_STOR)
+        self.storage.to_owned()
+    }
+
+    // Get the kernel, which modules are tracked
+    fn get_kinfo(&self) -> Result<&'a KernelInfo<'a>, std::io::Error> {
+        match self.kinfo {
+            Some(kinfo) => Ok(kinfo),
+            None => Err(std::io::Error::new(ErrorKind::InvalidInput, "Global module list is not bound to a kernel")),
+        }
     }
 
     /// Read used modules from the storage
     fn load(&mut self) -> Result<(), std::io::Error> {
         let st_pth = self.get_storage_path();
         if !st_pth.exists() {
-            log::warn!("No module storage index found. Skipping...");
+            if self.kinfo.is_some() {
+                log::warn!("No module storage index found. Skipping...");
+            } else if *self.debug {
+                log::debug!("No global module storage index found");
+            }
             return Ok(());
         }
 
@@ -88,20 +148,32 @@ impl<'a> ModList<'a> {
             return Err(rfp.err().unwrap());
         }
 
-        for mut data in io::BufReader::new(rfp.unwrap()).lines().flatten() {
+        for mut data in io::BufReader::new(rfp.unwrap()).lines().map_while(Result::ok) {
             data = data.trim().to_string();
             if data.starts_with('#') || data.is_empty() || !data.contains(':') {
                 continue;
             }
-            let state_kw: Vec<String> = data.split(':').map(|x| x.to_string()).collect();
-            if state_kw.len() != 2 {
+            // Marker is always the last, as module patterns may contain colons, e.g. "re:^nf_:1"
+            let (name, marker) = data.rsplit_once(':').unwrap();
+            if name.is_empty() || marker.is_empty() {
                 log::warn!("Suspicious entry found: {}. Skipping...", data);
                 continue;
             }
 
-            let state_ptr: i16 = if state_kw[1] == "S" { -1 } else { state_kw[1].to_string().parse::<i16>().unwrap() };
-
-            self.modlist.insert(state_kw[0].to_owned(), state_ptr);
+            let state_ptr: i16 = match marker {
+                "S" => -1,
+                "X" => EXCLUDED,
+                "A" => AUTOLOAD,
+                _ => match marker.parse::<i16>() {
+                    Ok(refs) if refs > 0 => refs,
+                    _ => {
+                        log::warn!("Suspicious entry found: {}. Skipping...", data);
+                        continue;
+                    }
+                },
+            };
+
+            self.modlist.insert(name.to_owned(), state_ptr);
         }
 
         Ok(())
@@ -110,6 +182,9 @@ impl<'a> ModList<'a> {
     /// Write data of used modules to the storage
     fn write(&self) -> Result<(), std::io::Error> {
         let sp = self.get_storage_path();
+        if let Some(parent) = sp.parent() {
+            fs::create_dir_all(parent)?;
+        }
         log::info!("Writing to {}", format!("{:?}", sp.as_path()).bright_yellow());
         let f_res = File::create(sp);
         if f_res.is_err() {
@@ -123,7 +198,17 @@ impl<'a> ModList<'a> {
 
         for (modname, modstate) in &self.modlist {
             let rw: Result<(), io::Error> = f_ptr.write_all(
-                format!("{}:{}\n", modname, if modstate < &0 { "S".to_string() } else { modstate.to_string() }).as_bytes(),
+                format!(
+                    "{}:{}\n",
+                    modname,
+                    match *modstate {
+                        s if s == EXCLUDED => "X".to_string(),
+                        s if s == AUTOLOAD => "A".to_string(),
+                        s if s < 0 => "S".to_string(),
+                        s => s.to_string(),
+                    }
+                )
+                .as_bytes(),
             );
 
             if rw.is_err() {
@@ -137,10 +222,16 @@ impl<'a> ModList<'a> {
     /// Add a main module (no dependencies to in). This increases the counter, but doesn't write anything to a disk.
     pub fn add(&mut self, name: String, is_static: bool) {
         match self.modlist.get(&name) {
+            Some(refcount) if *refcount == EXCLUDED => {
+                log::info!("Including back {}module \"{}\"", if is_static { "static " } else { "" }, name.bright_yellow());
+                self.modlist.insert(name, if is_static { -1 } else { 1 });
+            }
             Some(refcount) => {
                 if *refcount > 0 {
                     log::info!("Updating {}module \"{}\"", if is_static { "static " } else { "" }, name.bright_yellow());
                     self.modlist.insert(name, refcount + 1);
+                } else if *refcount == AUTOLOAD {
+                    log::warn!("Skipping module \"{}\", imported as loaded at boot", name.bright_yellow());
                 } else {
                     log::warn!("Skipping static module \"{}\"", name.bright_yellow());
                 }
@@ -158,14 +249,61 @@ impl<'a> ModList<'a> {
         self.write()
     }
 
+    /// Exclude a module for this kernel, even if it is in the global list.
+    /// This replaces any other state of the module, but doesn't write anything to a disk.
+    pub fn exclude(&mut self, name: String) {
+        log::info!("Excluding module \"{}\"", name.bright_yellow());
+        self.modlist.insert(name, EXCLUDED);
+    }
+
     /// Get indexed modules
     pub fn get_modules(&self) -> Vec<String> {
-        let mut out: Vec<String> = self.modlist.keys().map(|s| s.to_owned()).collect();
+        let mut out: Vec<String> = self.modlist.iter().filter(|(_, s)| **s != EXCLUDED).map(|(n, _)| n.to_owned()).collect();
         out.sort();
 
         out
     }
 
+    /// Get modules, those are indexed in this kernel and in the global list,
+    /// except those excluded in this kernel.
+    pub fn get_effective_modules(&self, global: &ModList) -> Vec<String> {
+        let excluded: Vec<String> =
+            self.modlist.iter().filter(|(_, s)| **s == EXCLUDED).map(|(n, _)| kman::get_module_name(n)).collect();
+
+        let mut out = self.get_modules();
+        for name in global.get_modules() {
+            if excluded.contains(&kman::get_module_name(&name)) {
+                if *self.debug {
+                    log::debug!("Skipping globally indexed module \"{}\", as it is excluded", name);
+                }
+            } else if !out.contains(&name) {
+                out.push(name);
+            }
+        }
+        out.sort();
+
+        out
+    }
+
+    /// Get indexed modules with their states: references counter or -1 for a static module
+    pub fn get_entries(&self) -> Vec<(String, i16)> {
+        let mut out: Vec<(String, i16)> = self.modlist.iter().map(|(n, s)| (n.to_owned(), *s)).collect();
+        out.sort();
+
+        out
+    }
+
+    /// Set a module with its state as is, unless it is already indexed.
+    /// Returns false if the module was already there. This doesn't write anything to a disk.
+    pub fn set(&mut self, name: String, state: i16) -> bool {
+        if self.modlist.contains_key(&name) {
+            return false;
+        }
+
+        self.modlist.insert(name, state);
+        true
+    }
+
     /// Remove a module from the tree.
     ///
     /// Note, it does not removes a module from the list iff there are no more counters
@@ -178,6 +316,18 @@ impl<'a> ModList<'a> {
         }
 
         let state: &mut i16 = &mut optval.as_mut().unwrap().to_owned();
+        if *state == EXCLUDED {
+            log::info!("Removing exclusion of \"{}\"", name);
+            self.modlist.remove(&name);
+            return Ok(());
+        }
+
+        if *state == AUTOLOAD {
+            log::info!("Removing \"{}\", imported as loaded at boot", name);
+            self.modlist.remove(&name);
+            return Ok(());
+        }
+
         if *state > 0 {
             *state -= 1;
         }
@@ -204,21 +354,27 @@ impl<'a> ModList<'a> {
         Ok(())
     }
 
-    /// Apply changes on a disk: remove from the media unused modules
-    pub fn commit(&self, modules: &[String]) -> Result<(), std::io::Error> {
+    /// Apply changes on a disk: remove from the media unused modules.
+    /// If quarantine directory is specified, modules are moved there instead,
+    /// keeping their relative paths under the kernel version.
+    pub fn commit(&self, modules: &[String], quarantine: Option<&Path>) -> Result<(), std::io::Error> {
         log::info!("Applying changes to {} modules", modules.len());
         let mut skipped = 0;
         let mut removed = 0;
 
-        for modpath in modules {
-            let modpath = &self.kinfo.get_kernel_path().join(modpath);
+        let kinfo = self.get_kinfo()?;
+        for modname in modules {
+            let modpath = &kinfo.get_kernel_path().join(modname);
             let s_modpath = modpath.to_owned().into_os_string().into_string().unwrap();
             if *self.debug {
                 log::debug!("Removing kernel module: {}", s_modpath);
             }
 
             if modpath.exists() {
-                fs::remove_file(modpath)?;
+                match quarantine {
+                    Some(quarantine) => move_file(modpath, &quarantine.join(&kinfo.version).join(modname))?,
+                    None => fs::remove_file(modpath)?,
+                }
                 removed += 1;
             } else {
                 if *self.debug {
@@ -229,7 +385,8 @@ impl<'a> ModList<'a> {
         }
 
         log::info!(
-            "Removed: {}, skipped (do not exist on the media): {}",
+            "{}: {}, skipped (do not exist on the media): {}",
+            if quarantine.is_some() { "Moved to quarantine" } else { "Removed" },
             removed.to_string().bright_yellow(),
             skipped.to_string().bright_yellow()
         );
@@ -243,7 +400,7 @@ impl<'a> ModList<'a> {
         let mut paths: Vec<_> = vec![];
 
         // Get directories, but do not remove them just yet
-        for e in WalkDir::new(self.kinfo.get_kernel_path().join("kernel")).into_iter().flatten() {
+        for e in WalkDir::new(self.get_kinfo()?.get_kernel_path().join("kernel")).into_iter().flatten() {
             if e.file_type().is_dir() {
                 paths.push(e.path().to_owned());
             }
@@ -278,3 +435,60 @@ impl<'a> ModList<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ModList, AUTOLOAD, EXCLUDED};
+    use std::{collections::HashMap, env, fs, path::PathBuf, process};
+
+    static DEBUG: bool = false;
+
+    fn load(path: &PathBuf) -> ModList<'static> {
+        let mut ml = ModList { modlist: HashMap::default(), storage: path.to_owned(), kinfo: None, debug: &DEBUG };
+        ml.load().unwrap();
+        ml
+    }
+
+    fn tmp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("limopack-test-{}-{}", process::id(), name))
+    }
+
+    #[test]
+    fn markers_round_trip() {
+        let path = tmp_path("markers");
+        fs::write(&path, "kernel/drivers/net/tap.ko:S\nsunrpc:2\n# comment\n\nre:^nf_:1\nkernel/net/**:A\nfloppy:X\n").unwrap();
+
+        let expected: Vec<(String, i16)> = vec![
+            ("floppy".to_string(), EXCLUDED),
+            ("kernel/drivers/net/tap.ko".to_string(), -1),
+            ("kernel/net/**".to_string(), AUTOLOAD),
+            ("re:^nf_".to_string(), 1),
+            ("sunrpc".to_string(), 2),
+        ];
+        let ml = load(&path);
+        assert_eq!(ml.get_entries(), expected);
+
+        ml.save().unwrap();
+        assert_eq!(load(&path).get_entries(), expected);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn malformed_entries_are_skipped() {
+        let path = tmp_path("malformed");
+        fs::write(&path, "foo:bar\nbaz:0\nqux:-1\n:1\nquux:\nnocolon\nsunrpc:1\n").unwrap();
+        assert_eq!(load(&path).get_entries(), vec![("sunrpc".to_string(), 1)]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn autoload_is_removable() {
+        let path = tmp_path("autoload");
+        fs::write(&path, "snd:A\ntap:S\n").unwrap();
+        let mut ml = load(&path);
+        ml.remove("snd".to_string()).unwrap();
+        ml.remove("tap".to_string()).unwrap();
+        assert_eq!(ml.get_entries(), vec![("tap".to_string(), -1)]);
+        fs::remove_file(path).unwrap();
+    }
+}
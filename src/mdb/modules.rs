@@ -5,6 +5,7 @@ pub mod modinfo {
 
     /// ModInfo contains current live module information
     #[derive(Debug)]
+    #[allow(dead_code)]
     pub struct ModInfo {
         pub name: String,
         pub mem_size: usize,
@@ -0,0 +1,103 @@
+//! Named module profiles, those are recurring sets of modules (e.g. networking,
+//! USB storage, virtio), kept in /etc/limopack/profiles.d/<name>.profile files.
+//!
+//! Format of a profile is a list of modules, one per a line, as they are passed to
+//! "--use" option. Other profiles can be included by their names. Example:
+//!
+//!     # Networking over Wi-Fi
+//!     include networking
+//!     iwlwifi
+//!     kernel/net/wireless/cfg80211.ko
+
+use colored::Colorize;
+use std::{
+    fs,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+pub static PROFILES_D: &str = "/etc/limopack/profiles.d";
+static PROFILE_EXT: &str = "profile";
+static INCLUDE_KW: &str = "include";
+
+/// Get path of a profile by its name
+fn get_profile_path(name: &str) -> Result<PathBuf, Error> {
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid profile name: \"{}\"", name)));
+    }
+
+    Ok(Path::new(PROFILES_D).join(format!("{}.{}", name, PROFILE_EXT)))
+}
+
+/// Read modules of a profile and those it includes, while keeping track of
+/// the chain of inclusions, so a profile cannot include itself.
+fn read_profile(name: &str, chain: &mut Vec<String>, loaded: &mut Vec<String>, modules: &mut Vec<String>) -> Result<(), Error> {
+    if chain.iter().any(|p| p == name) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Profile \"{}\" includes itself: {} -> {}", name, chain.join(" -> "), name),
+        ));
+    }
+
+    // Already included by another profile
+    if loaded.iter().any(|p| p == name) {
+        return Ok(());
+    }
+
+    let path = get_profile_path(name)?;
+    let data = match fs::read_to_string(&path) {
+        Ok(data) => data,
+        Err(err) => {
+            return Err(Error::new(
+                err.kind(),
+                format!("Unable to read profile \"{}\" at {}: {}", name, path.to_str().unwrap().bright_yellow(), err),
+            ))
+        }
+    };
+
+    chain.push(name.to_string());
+    for line in data.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line.split_once(char::is_whitespace) {
+            Some((kw, includes)) if kw == INCLUDE_KW => {
+                for include in includes.split_whitespace() {
+                    read_profile(include, chain, loaded, modules)?;
+                }
+            }
+            _ => {
+                if !modules.iter().any(|m| m == line) {
+                    modules.push(line.to_string());
+                }
+            }
+        }
+    }
+    chain.pop();
+    loaded.push(name.to_string());
+
+    Ok(())
+}
+
+/// Get modules of the specified profiles, including all profiles they include.
+pub fn get_modules(names: &[String], debug: &bool) -> Result<Vec<String>, Error> {
+    let mut modules: Vec<String> = vec![];
+    let mut loaded: Vec<String> = vec![];
+
+    for name in names {
+        let before = modules.len();
+        read_profile(name, &mut vec![], &mut loaded, &mut modules)?;
+
+        if *debug {
+            log::debug!("Profile \"{}\" adds {} modules", name, modules.len() - before);
+        }
+    }
+
+    // Empty set of modules means all currently loaded modules otherwise
+    if modules.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, format!("No modules found in profiles: {}", names.join(", "))));
+    }
+
+    Ok(modules)
+}
@@ -1,5 +1,8 @@
 pub mod kman {
+    use crate::{config, sysutils};
+    use regex::Regex;
     use std::fs::{read_dir, read_to_string};
+    use std::io::{Error, ErrorKind};
     use std::path::{Path, PathBuf};
     use std::{
         collections::{HashMap, HashSet},
@@ -9,6 +12,119 @@ pub mod kman {
     pub static MOD_D: &str = "/lib/modules";
     pub static MOD_DEP_F: &str = "modules.dep";
     pub static MOD_INFO_EXE: &str = "/usr/sbin/modinfo";
+    pub static MOD_EXT: [&str; 4] = [".ko", ".ko.xz", ".ko.zst", ".ko.gz"];
+
+    /// Get the root directory of kernel modules, which is /lib/modules, unless configured otherwise
+    pub fn get_modules_root() -> PathBuf {
+        PathBuf::from(config::get().modules_root.unwrap_or_else(|| MOD_D.to_string()))
+    }
+
+    static RE_PREFIX: &str = "re:";
+
+    /// Get a module path or name without ".ko" extension (possibly compressed)
+    fn strip_ext(name: &str) -> &str {
+        for ext in MOD_EXT.iter().rev() {
+            if let Some(n) = name.strip_suffix(ext) {
+                return n;
+            }
+        }
+
+        name
+    }
+
+    /// Get a module path or name without ".ko" extension (possibly compressed)
+    /// and with dashes replaced by underscores, as module names are equal either way.
+    /// Example: "kernel/sound/soc/snd-soc-core.ko.zst" -> "kernel/sound/soc/snd_soc_core"
+    pub fn strip_module_ext(name: &str) -> String {
+        strip_ext(name).replace('-', "_")
+    }
+
+    /// Get a module name from its path or name.
+    /// Example: "kernel/sound/soc/snd-soc-core.ko.zst" -> "snd_soc_core"
+    pub fn get_module_name(name: &str) -> String {
+        strip_module_ext(name.rsplit('/').next().unwrap_or_default())
+    }
+
+    /// Returns true if a module is specified as a pattern: either a regular expression
+    /// with "re:" prefix, or a glob with "*", "**" or "?" wildcards or "[...]" classes.
+    pub fn is_module_pattern(name: &str) -> bool {
+        name.starts_with(RE_PREFIX) || sysutils::is_wildcard(name)
+    }
+
+    /// Get a glob of module names without ".ko" extension and with dashes replaced by
+    /// underscores, the same way as module names are. Dashes of classes are ranges, so
+    /// they are kept as is.
+    fn strip_glob_ext(glob: &str) -> String {
+        let mut out = String::default();
+        let mut in_class = false;
+        for c in strip_ext(glob).chars() {
+            match c {
+                '[' => in_class = true,
+                ']' => in_class = false,
+                _ => {}
+            }
+            out.push(if c == '-' && !in_class { '_' } else { c });
+        }
+
+        out
+    }
+
+    /// Pattern, selecting modules by their paths or names.
+    ///
+    /// Globs with a slash are matched against module paths, where "**" matches
+    /// any number of directories, "*" and "?" match within a directory only and
+    /// "[...]" matches a class of characters.
+    /// Globs without a slash are matched against module names. Extensions are
+    /// not taken into account and dashes are equal to underscores in names.
+    /// Regular expressions are searched both in module names and paths.
+    ///
+    /// Examples:
+    ///
+    ///     kernel/drivers/net/wireless/**
+    ///     snd-soc-*
+    ///     re:^nf_
+    pub struct ModPattern {
+        re: Regex,
+        by_path: Option<bool>,
+    }
+
+    impl ModPattern {
+        pub fn new(pattern: &str) -> Result<Self, Error> {
+            if let Some(expr) = pattern.strip_prefix(RE_PREFIX) {
+                return match Regex::new(expr) {
+                    Ok(re) => Ok(ModPattern { re, by_path: None }),
+                    Err(err) => {
+                        Err(Error::new(ErrorKind::InvalidInput, format!("Invalid module pattern \"{}\": {}", pattern, err)))
+                    }
+                };
+            }
+
+            let by_path = pattern.contains('/');
+            let glob = if by_path { strip_ext(pattern).to_string() } else { strip_glob_ext(pattern) };
+
+            let mut expr = String::from("^");
+            if by_path && !glob.starts_with("kernel/") {
+                expr.push_str("(.*/)?"); // Partial path, such as "net/wireless/**"
+            }
+
+            expr.push_str(&sysutils::glob_to_regex(&glob));
+            expr.push('$');
+
+            match Regex::new(&expr) {
+                Ok(re) => Ok(ModPattern { re, by_path: Some(by_path) }),
+                Err(err) => Err(Error::new(ErrorKind::InvalidInput, format!("Invalid module pattern \"{}\": {}", pattern, err))),
+            }
+        }
+
+        /// Returns true if a module path or name matches the pattern
+        pub fn matches(&self, name: &str) -> bool {
+            match self.by_path {
+                Some(true) => self.re.is_match(strip_ext(name)),
+                Some(false) => self.re.is_match(&get_module_name(name)),
+                None => self.re.is_match(strip_ext(name.rsplit('/').next().unwrap_or_default())) || self.re.is_match(name),
+            }
+        }
+    }
 
     /// Metadata about the kernel and details about it
     #[derive(Debug, Clone)]
@@ -28,7 +144,7 @@ pub mod kman {
         pub fn new(kver: &str, debug: &'kinfo bool) -> Self {
             KernelInfo {
                 version: kver.to_owned(),
-                path: PathBuf::from(MOD_D),
+                path: get_modules_root(),
                 dep_path: PathBuf::from(""),
                 deplist: HashMap::default(),
                 _loaded: false,
@@ -54,7 +170,7 @@ pub mod kman {
 
         /// Return current kernel info root path.
         pub fn get_kernel_path(&self) -> PathBuf {
-            PathBuf::from(MOD_D).join(&self.version)
+            self.path.to_owned()
         }
 
         /// Load module dependencies
@@ -87,7 +203,7 @@ pub mod kman {
 
         /// Returns true if there are actual modules on the media for this kernel.
         /// There are often kernel paths left after a kernel was not completely purged.
-        fn is_valid(&self) -> bool {
+        pub fn is_valid(&self) -> bool {
             self.is_valid
         }
 
@@ -97,33 +213,47 @@ pub mod kman {
             self.dep_path.to_str().unwrap()
         }
 
+        /// Find a module in the dependencies of this kernel only, without asking "modinfo",
+        /// which is only aware of the running kernel. Compressed modules are also matched.
+        /// Example: "sunrpc", "sunrpc.ko" or "net/sunrpc/sunrpc.ko" will be resolved
+        /// as "kernel/net/sunrpc/sunrpc.ko.zst"
+        pub fn find_module(&self, name: &str) -> Option<&String> {
+            if let Some((fmodname, _)) = self.deplist.get_key_value(name) {
+                return Some(fmodname);
+            }
+
+            let m_name = strip_module_ext(name);
+            let m_sfx = format!("/{}", m_name.trim_start_matches('/'));
+            let mut found: Vec<&String> = self
+                .deplist
+                .keys()
+                .filter(|fmodname| {
+                    let f_name = strip_module_ext(fmodname);
+                    f_name == m_name || f_name.ends_with(&m_sfx)
+                })
+                .collect();
+
+            // Same module can be only once per a kernel, but be deterministic anyway
+            found.sort();
+            found.first().copied()
+        }
+
+        /// Find all modules on the disk, matching a pattern
+        pub fn find_modules(&self, pattern: &ModPattern) -> Vec<&String> {
+            let mut found: Vec<&String> = self.deplist.keys().filter(|fmodname| pattern.matches(fmodname)).collect();
+            found.sort();
+
+            found
+        }
+
         /// Find a full path to a module
         /// Example: "sunrpc.ko" will be resolved as "kernel/net/sunrpc/sunrpc.ko"
         ///
         /// Some modules are named differently on the disk than in the memory.
         /// In this case they are tried to be resolved via external "modinfo".
-        fn expand_module_name<'a>(&'a self, name: &'a String) -> &String {
-            let mut m_name: String;
-            if !name.ends_with(".ko") {
-                m_name = format!("{}.ko", name); // "sunrpc" -> "sunrpc.ko"
-            } else {
-                m_name = name.to_owned();
-            }
-
-            if !m_name.starts_with("kernel/") {
-                // name or partial path
-                if !m_name.contains('/') {
-                    m_name = format!("/{}", m_name); // "sunrpc.ko" -> "/sunrpc.ko"
-                }
-
-                for fmodname in self.deplist.keys() {
-                    // Eliminate to a minimum 3rd fallback via modinfo by trying replacing underscore with a minus.
-                    // This not always works, because some modules called mixed.
-                    let mm_name = m_name.replace('_', "-");
-                    if fmodname.ends_with(&m_name) || fmodname.ends_with(&mm_name) {
-                        return fmodname;
-                    }
-                }
+        fn expand_module_name<'a>(&'a self, name: &'a String) -> &'a String {
+            if let Some(fmodname) = self.find_module(name) {
+                return fmodname;
             }
 
             let out = Command::new(MOD_INFO_EXE).arg(name).output();
@@ -171,21 +301,40 @@ pub mod kman {
         pub fn get_deps_for(&self, names: &[String]) -> HashMap<String, Vec<String>> {
             let mut mod_tree: HashMap<String, Vec<String>> = HashMap::new();
             for kmodname in names {
-                let r_kmodname = self.expand_module_name(kmodname);
-                if !r_kmodname.contains('/') {
-                    log::warn!("Module not found on a disk: {}", r_kmodname);
-                    continue;
-                }
+                let r_kmodnames: Vec<&String> = if is_module_pattern(kmodname) {
+                    match ModPattern::new(kmodname) {
+                        Ok(pattern) => {
+                            let found = self.find_modules(&pattern);
+                            if found.is_empty() {
+                                log::warn!("No modules found on a disk, matching: {}", kmodname);
+                            }
+                            found
+                        }
+                        Err(err) => {
+                            log::error!("{}", err);
+                            continue;
+                        }
+                    }
+                } else {
+                    let r_kmodname = self.expand_module_name(kmodname);
+                    if !r_kmodname.contains('/') {
+                        log::warn!("Module not found on a disk: {}", r_kmodname);
+                        continue;
+                    }
+                    vec![r_kmodname]
+                };
 
-                let mut mod_deps: HashSet<String> = HashSet::default();
-                let mut r_deps: Vec<String> = vec![];
+                for r_kmodname in r_kmodnames {
+                    let mut mod_deps: HashSet<String> = HashSet::default();
+                    let mut r_deps: Vec<String> = vec![];
 
-                self.get_mod_dep(r_kmodname, &mut mod_deps);
+                    self.get_mod_dep(r_kmodname, &mut mod_deps);
 
-                for v in mod_deps {
-                    r_deps.push(v);
+                    for v in mod_deps {
+                        r_deps.push(v);
+                    }
+                    mod_tree.insert(r_kmodname.to_owned(), r_deps);
                 }
-                mod_tree.insert(r_kmodname.to_owned(), r_deps);
             }
 
             mod_tree
@@ -220,9 +369,9 @@ pub mod kman {
     }
 
     /// Get the list of existing kernels in the system.
-    pub fn get_kernel_infos(debug: &bool) -> Vec<KernelInfo> {
+    pub fn get_kernel_infos(debug: &bool) -> Vec<KernelInfo<'_>> {
         let mut kernels: Vec<KernelInfo> = vec![];
-        for fres in read_dir(MOD_D).unwrap() {
+        for fres in read_dir(get_modules_root()).unwrap() {
             let fd = fres.unwrap();
             if fd.file_type().unwrap().is_dir() {
                 let kinfo: KernelInfo<'_> = KernelInfo::new(fd.path().file_name().unwrap().to_str().unwrap(), debug);
@@ -235,3 +384,58 @@ pub mod kman {
         kernels
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::kman::{is_module_pattern, ModPattern};
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        ModPattern::new(pattern).unwrap().matches(name)
+    }
+
+    #[test]
+    fn patterns_are_detected() {
+        for pattern in ["re:^nf_", "snd-*", "kernel/drivers/net/**", "snd-?", "nf_conntrack_[a-z]"] {
+            assert!(is_module_pattern(pattern), "{}", pattern);
+        }
+        for name in ["kernel/drivers/net/tap.ko", "snd-soc-core", "re"] {
+            assert!(!is_module_pattern(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn path_globs_match_paths() {
+        assert!(matches("kernel/drivers/net/wireless/**", "kernel/drivers/net/wireless/intel/iwlwifi/iwlwifi.ko.zst"));
+        assert!(!matches("kernel/drivers/net/wireless/**", "kernel/drivers/net/tap.ko"));
+        assert!(matches("kernel/drivers/net/*", "kernel/drivers/net/tap.ko"));
+        assert!(!matches("kernel/drivers/net/*", "kernel/drivers/net/wireless/cfg80211.ko"));
+
+        // Partial paths match at any directory
+        assert!(matches("net/wireless/**", "kernel/drivers/net/wireless/cfg80211.ko"));
+        assert!(!matches("kernel/net/**", "updates/kernel/net/foo.ko"));
+    }
+
+    #[test]
+    fn name_globs_match_names() {
+        assert!(matches("snd-soc-*", "kernel/sound/soc/snd-soc-core.ko.xz"));
+        assert!(matches("snd_soc_*", "kernel/sound/soc/snd-soc-core.ko"));
+        assert!(matches("snd-soc-*", "snd_soc_core"));
+        assert!(matches("iwlwifi.ko", "kernel/drivers/net/wireless/iwlwifi.ko.zst"));
+        assert!(!matches("snd-soc-*", "kernel/sound/snd.ko"));
+    }
+
+    #[test]
+    fn name_classes_keep_ranges() {
+        assert!(matches("nf_conntrack_[a-z]*", "kernel/net/netfilter/nf_conntrack_ftp.ko"));
+        assert!(!matches("nf_conntrack_[!a-z]*", "kernel/net/netfilter/nf_conntrack_ftp.ko"));
+        assert!(matches("snd-hda-codec-[ch]*", "snd-hda-codec-hdmi"));
+    }
+
+    #[test]
+    fn regular_expressions_match_names_and_paths() {
+        assert!(matches("re:^nf_", "kernel/net/netfilter/nf_nat.ko"));
+        assert!(matches("re:netfilter/", "kernel/net/netfilter/nf_nat.ko"));
+        assert!(!matches("re:^nf_", "kernel/net/ipv4/netfilter/iptable_nat.ko"));
+        assert!(ModPattern::new("re:(").is_err());
+    }
+}
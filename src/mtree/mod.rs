@@ -1,2 +1,3 @@
 pub mod kerman;
 pub mod moddeps;
+pub mod usage;
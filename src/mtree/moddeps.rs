@@ -25,9 +25,8 @@ pub mod ktree {
 
         /// Get all dependencies for the specified modules
         pub fn get_specified_deps(&self, modules: &[String]) -> HashMap<String, Vec<String>> {
-            match modules.is_empty() {
-                true => return self.kernel.get_deps_for(&self.get_loaded_modules()),
-                false => (),
+            if modules.is_empty() {
+                return self.kernel.get_deps_for(&self.get_loaded_modules());
             }
 
             self.kernel.get_deps_for(modules)
@@ -0,0 +1,228 @@
+//! Disk usage of modules of a kernel: how much is kept and how much is pruned,
+//! also per a directory (e.g. drivers/net, sound, fs), so the result can be
+//! checked against a size budget. Also cost of each kept module: size of its
+//! dependency closure and size of the part, which only it keeps on the disk.
+
+use crate::mtree::kerman::kman::KernelInfo;
+use colored::Colorize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+};
+
+static UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+/// Format size in bytes in human-readable units
+pub fn format_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Get size of a module of a kernel on the disk
+pub fn get_module_size(ki: &KernelInfo, modname: &str) -> u64 {
+    fs::metadata(ki.get_kernel_path().join(modname)).map(|m| m.len()).unwrap_or(0)
+}
+
+/// Get a directory of a module to account it to: subsystem of drivers (e.g. "drivers/net"),
+/// otherwise the top directory under "kernel" (e.g. "sound") or the top directory itself
+/// for out-of-tree modules (e.g. "updates").
+fn get_usage_dir(modname: &str) -> String {
+    let parts: Vec<&str> = modname.strip_prefix("kernel/").unwrap_or(modname).split('/').collect();
+    match parts.as_slice() {
+        ["drivers", sub, _, ..] => format!("drivers/{}", sub),
+        [dir, _, ..] => dir.to_string(),
+        _ => ".".to_string(),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DiskUsage {
+    pub total: u64,
+    pub kept: u64,
+    pub pruned: u64,
+
+    // Directory to kept and pruned bytes
+    pub dirs: BTreeMap<String, (u64, u64)>,
+}
+
+impl DiskUsage {
+    /// Account sizes of modules of a kernel on the disk, those are either kept or pruned
+    pub fn new(ki: &KernelInfo, disk_mods: &[String], pruned: &[String]) -> Self {
+        let mut usage = DiskUsage::default();
+        let pruned: HashSet<&String> = pruned.iter().collect();
+        for m in disk_mods {
+            let size = get_module_size(ki, m);
+            let dir = usage.dirs.entry(get_usage_dir(m)).or_insert((0, 0));
+            if pruned.contains(m) {
+                usage.pruned += size;
+                dir.1 += size;
+            } else {
+                usage.kept += size;
+                dir.0 += size;
+            }
+            usage.total += size;
+        }
+
+        usage
+    }
+
+    /// Log the usage summary and the breakdown per a directory
+    pub fn log(&self, version: &str) {
+        log::info!(
+            "Disk usage of kernel {}: total {}, kept {}, to prune {}",
+            version,
+            format_size(self.total).bright_yellow(),
+            format_size(self.kept).bright_yellow(),
+            format_size(self.pruned).bright_yellow()
+        );
+
+        for (dir, (kept, pruned)) in &self.dirs {
+            log::info!("  {}: kept {}, to prune {}", dir, format_size(*kept), format_size(*pruned));
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ModuleCost {
+    pub name: String,
+
+    // Number of modules in the dependency closure and their size
+    pub modules: usize,
+    pub closure: u64,
+
+    // Size of modules, which are kept only by this module, i.e. freed once it is removed
+    pub exclusive: u64,
+}
+
+/// Get cost of each of registered modules of a kernel, most expensive first. Modules,
+/// kept implicitly, are not reported, but they keep their dependencies as well.
+pub fn get_costs(ki: &KernelInfo, registered: &[String], implicit: &[String]) -> Vec<ModuleCost> {
+    calc_costs(registered, implicit, |name| ki.get_deps_for(&[name.to_owned()]), |m| get_module_size(ki, m))
+}
+
+/// Calculate costs of registered modules with a resolver of a module (or a pattern) to its root
+/// modules with their dependencies, and sizes of modules. Modules are accounted to root modules,
+/// so entries, resolved to the same modules (e.g. a pattern and a module it matches, or a name
+/// and a path), share them: a module is exclusive to an entry, if it is kept only by its roots.
+fn calc_costs<R, S>(registered: &[String], implicit: &[String], resolve: R, size: S) -> Vec<ModuleCost>
+where
+    R: Fn(&String) -> HashMap<String, Vec<String>>,
+    S: Fn(&String) -> u64,
+{
+    // Root modules of each entry with their dependency closures
+    let mut entries: Vec<(&String, HashMap<String, HashSet<String>>)> = vec![];
+    for name in registered.iter().chain(implicit) {
+        let roots = resolve(name).into_iter().map(|(m, deps)| (m.to_owned(), deps.into_iter().chain([m]).collect())).collect();
+        entries.push((name, roots));
+    }
+
+    // Distinct root modules, keeping each module, and those kept implicitly
+    let mut keepers: HashMap<&String, HashSet<&String>> = HashMap::default();
+    let mut implicit_roots: HashSet<&String> = HashSet::default();
+    for (idx, (_, roots)) in entries.iter().enumerate() {
+        for (root, closure) in roots {
+            if idx >= registered.len() {
+                implicit_roots.insert(root);
+            }
+            for m in closure {
+                keepers.entry(m).or_default().insert(root);
+            }
+        }
+    }
+
+    let mut out: Vec<ModuleCost> = entries
+        .iter()
+        .take(registered.len())
+        .map(|(name, roots)| {
+            let closure: HashSet<&String> = roots.values().flatten().collect();
+            let is_exclusive = |m: &String| keepers[m].iter().all(|r| roots.contains_key(*r) && !implicit_roots.contains(r));
+            ModuleCost {
+                name: name.to_string(),
+                modules: closure.len(),
+                closure: closure.iter().map(|m| size(m)).sum(),
+                exclusive: closure.iter().filter(|m| is_exclusive(m)).map(|m| size(m)).sum(),
+            }
+        })
+        .collect();
+    out.sort_by(|a, b| b.exclusive.cmp(&a.exclusive).then(b.closure.cmp(&a.closure)).then(a.name.cmp(&b.name)));
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{calc_costs, ModuleCost};
+    use std::collections::HashMap;
+
+    // Module, its size and dependencies
+    static MODULES: [(&str, u64, &[&str]); 6] = [
+        ("kernel/sound/snd.ko", 100, &[]),
+        ("kernel/sound/soc/snd-soc-core.ko", 20, &["kernel/sound/snd.ko"]),
+        ("kernel/sound/soc/snd-soc-extra.ko", 3, &["kernel/sound/snd.ko"]),
+        ("kernel/drivers/net/tap.ko", 7, &[]),
+        ("kernel/fs/ext4/ext4.ko", 50, &["kernel/lib/crc16.ko"]),
+        ("kernel/lib/crc16.ko", 5, &[]),
+    ];
+
+    fn get_costs(registered: &[&str], implicit: &[&str]) -> Vec<ModuleCost> {
+        let resolve = |name: &String| {
+            MODULES
+                .iter()
+                .filter(|(m, _, _)| match name.strip_suffix('*') {
+                    Some(prefix) => m.rsplit('/').next().unwrap().starts_with(prefix),
+                    None => m == name || m.rsplit('/').next().unwrap().strip_suffix(".ko") == Some(name),
+                })
+                .map(|(m, _, deps)| (m.to_string(), deps.iter().map(|d| d.to_string()).collect()))
+                .collect::<HashMap<String, Vec<String>>>()
+        };
+        let size = |m: &String| MODULES.iter().find(|(name, _, _)| name == m).unwrap().1;
+
+        let registered: Vec<String> = registered.iter().map(|m| m.to_string()).collect();
+        let implicit: Vec<String> = implicit.iter().map(|m| m.to_string()).collect();
+        calc_costs(&registered, &implicit, resolve, size)
+    }
+
+    fn get_exclusive(costs: &[ModuleCost], name: &str) -> u64 {
+        costs.iter().find(|c| c.name == name).unwrap().exclusive
+    }
+
+    #[test]
+    fn distinct_entries_own_their_closures() {
+        let costs = get_costs(&["snd-soc-core", "tap"], &[]);
+        assert_eq!(
+            costs.iter().map(|c| (c.name.as_str(), c.modules, c.closure, c.exclusive)).collect::<Vec<_>>(),
+            [("snd-soc-core", 2, 120, 120), ("tap", 1, 7, 7)]
+        );
+    }
+
+    #[test]
+    fn overlapping_entries_share_root_modules() {
+        // A pattern with a literal it matches, and a name with a path of the same module
+        let costs = get_costs(&["snd-soc-*", "snd-soc-core", "tap", "kernel/drivers/net/tap.ko"], &[]);
+        assert_eq!(get_exclusive(&costs, "snd-soc-*"), 123);
+        assert_eq!(get_exclusive(&costs, "snd-soc-core"), 20);
+        assert_eq!(get_exclusive(&costs, "tap"), 7);
+        assert_eq!(get_exclusive(&costs, "kernel/drivers/net/tap.ko"), 7);
+    }
+
+    #[test]
+    fn implicit_modules_are_never_exclusive() {
+        let costs = get_costs(&["ext4", "crc16"], &["ext4"]);
+        assert_eq!(costs.len(), 2);
+        assert_eq!(get_exclusive(&costs, "ext4"), 0);
+        assert_eq!(get_exclusive(&costs, "crc16"), 0);
+
+        let costs = get_costs(&["ext4"], &["crc16"]);
+        assert_eq!(get_exclusive(&costs, "ext4"), 50);
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    fs,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use colored::Colorize;
+
+/// This module removes a package from the Alpine's /lib/apk/db/installed database.
+/// Each package is a record of letter-prefixed lines (e.g. "P:" is a package name,
+/// "V:" is its version etc), separated by an empty line. Additionally the package
+/// is also taken away from the /etc/apk/world, so apk won't bring it back on its own,
+/// while it is considered as not installed.
+///
+/// The record and the world entries of a removed package are kept in
+/// /lib/apk/db/limopack.bkp/<pkg>/ as "installed" and "world" respectively,
+/// so it can be registered back again.
+///
+use super::{deb822, deb822::Deb822, rmpak::PackMod};
+use crate::sysutils;
+
+pub static APK_INSTALLED: &str = "/lib/apk/db/installed";
+pub static APK_WORLD: &str = "/etc/apk/world";
+
+#[derive(Clone)]
+pub struct ApkMod<'a> {
+    packages: Deb822,
+    world: Vec<String>,
+
+    // Removed packages with their records and world entries
+    removed: Vec<(String, Vec<String>, Vec<String>)>,
+
+    // Backup directories of the packages that are registered back
+    restored: Vec<PathBuf>,
+    installed_path: String,
+    world_path: String,
+    debug: &'a bool,
+}
+
+impl<'a> ApkMod<'a> {
+    pub fn new(debug: &'a bool, root: &Path) -> Self {
+        ApkMod {
+            packages: Deb822::default(),
+            world: vec![],
+            removed: vec![],
+            restored: vec![],
+            installed_path: sysutils::in_root(root, APK_INSTALLED),
+            world_path: sysutils::in_root(root, APK_WORLD),
+            debug,
+        }
+        .load()
+    }
+
+    /// Load installed packages and the world
+    fn load(&mut self) -> Self {
+        self.packages = Deb822::load(&self.installed_path);
+        if let Ok(data) = fs::read_to_string(&self.world_path) {
+            self.world.extend(data.split_whitespace().map(|x| x.to_string()));
+        }
+
+        self.to_owned()
+    }
+
+    /// Get a directory, where records of removed packages are kept
+    fn get_backup_path(&self) -> PathBuf {
+        Path::new(&self.installed_path).with_file_name("limopack.bkp")
+    }
+
+    /// Get a package name from the world entry, which might also have
+    /// a version constraint or a repository tag, e.g. "foo>=1.0" or "foo@edge".
+    fn get_world_name(entry: &str) -> &str {
+        match entry.find(|c| "<>=~@".contains(c)) {
+            Some(idx) => &entry[..idx],
+            None => entry,
+        }
+    }
+}
+
+impl PackMod for ApkMod<'_> {
+    /// Remove package from the index. This still keeps only the state of the modpack,
+    /// but does not writes anything to the disk.
+    fn remove_package(&mut self, pn: String) -> Result<(), Error> {
+        log::info!("Looking for \"{}\" package...", pn.bright_yellow());
+        let records = self.packages.remove_where(|p| deb822::get_field(p, "P").as_deref() == Some(pn.as_str()));
+        if records.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Package \"{}\" was not found in the database", pn.bright_yellow()),
+            ));
+        }
+
+        log::info!("Altering package manager database for \"{}\"", pn.bright_yellow());
+        let world: Vec<String> = self.world.iter().filter(|e| ApkMod::get_world_name(e) == pn).cloned().collect();
+        self.world.retain(|e| ApkMod::get_world_name(e) != pn);
+        self.removed.push((pn, records, world));
+
+        Ok(())
+    }
+
+    /// Register back a package, which was previously removed from the index.
+    fn restore_package(&mut self, pn: String) -> Result<(), Error> {
+        log::info!("Looking for \"{}\" package in backups...", pn.bright_yellow());
+        if self.packages.paragraphs().iter().any(|p| deb822::get_field(p, "P").as_deref() == Some(pn.as_str())) {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("Package \"{}\" is already registered in the database", pn.bright_yellow()),
+            ));
+        }
+
+        let pkg_path = self.get_backup_path().join(&pn);
+        let data = match fs::read_to_string(pkg_path.join("installed")) {
+            Ok(data) => data,
+            Err(_) => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!(
+                        "Package \"{}\" was not found in backups at {}",
+                        pn.bright_yellow(),
+                        self.get_backup_path().to_str().unwrap()
+                    ),
+                ))
+            }
+        };
+
+        log::info!("Restoring \"{}\" from {}", pn.bright_yellow(), pkg_path.to_str().unwrap().bright_yellow());
+        for p in Deb822::parse(&data).paragraphs() {
+            self.packages.insert(p);
+        }
+        for e in fs::read_to_string(pkg_path.join("world")).unwrap_or_default().split_whitespace() {
+            if !self.world.iter().any(|w| w == e) {
+                self.world.push(e.to_string());
+            }
+        }
+        self.restored.push(pkg_path);
+
+        Ok(())
+    }
+
+    /// Get names of all packages in the database.
+    fn list_packages(&self) -> Vec<String> {
+        self.packages.get_field_values("P")
+    }
+
+    /// Save the current state to the disk.
+    fn save(&self) -> Result<(), Error> {
+        log::info!("Save changes to the apk database");
+        deb822::write_with_backup(&self.installed_path, &self.packages.to_string(), self.debug)?;
+        if Path::new(&self.world_path).exists() {
+            deb822::write_with_backup(&self.world_path, &format!("{}\n", self.world.join("\n")), self.debug)?;
+        }
+
+        for (pn, records, world) in &self.removed {
+            let pkg_path = self.get_backup_path().join(pn);
+            fs::create_dir_all(&pkg_path)?;
+            fs::write(pkg_path.join("installed"), records.join("\n"))?;
+            fs::write(pkg_path.join("world"), world.iter().map(|e| format!("{}\n", e)).collect::<String>())?;
+        }
+
+        for pkg_path in &self.restored {
+            fs::remove_dir_all(pkg_path)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ApkMod, APK_INSTALLED, APK_WORLD};
+    use crate::{pakmod::rmpak::PackMod, sysutils};
+    use std::{
+        env, fs,
+        io::ErrorKind,
+        path::{Path, PathBuf},
+        process,
+    };
+
+    static INSTALLED: &str = "C:Q1abc=\nP:foo\nV:1.0-r0\nF:usr/bin\nR:foo\n\n\
+                              C:Q1def=\nP:bar\nV:2.0-r0\nF:usr/lib\nR:libbar.so\n\n";
+
+    /// Create an image root with apk database of "foo" and "bar" and the world
+    fn make_root(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("limopack-test-{}-{}", process::id(), name));
+        for (path, data) in [(APK_INSTALLED, INSTALLED), (APK_WORLD, "foo>=1.0\nbar\nfoo@edge\n")] {
+            let path = sysutils::in_root(&root, path);
+            fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+
+        root
+    }
+
+    #[test]
+    fn world_names_are_without_constraints() {
+        for entry in ["foo", "foo>=1.0", "foo<2", "foo~1.0", "foo=1.0-r0", "foo@edge"] {
+            assert_eq!(ApkMod::get_world_name(entry), "foo", "{}", entry);
+        }
+    }
+
+    #[test]
+    fn removed_package_is_restored() {
+        let root = make_root("apk-restore");
+        let mut am = ApkMod::new(&false, &root);
+        am.remove_package("foo".to_string()).unwrap();
+        am.save().unwrap();
+
+        assert_eq!(ApkMod::new(&false, &root).list_packages(), ["bar"]);
+        assert_eq!(fs::read_to_string(sysutils::in_root(&root, APK_WORLD)).unwrap(), "bar\n");
+
+        let mut am = ApkMod::new(&false, &root);
+        am.restore_package("foo".to_string()).unwrap();
+        am.save().unwrap();
+
+        let mut packages = ApkMod::new(&false, &root).list_packages();
+        packages.sort();
+        assert_eq!(packages, ["bar", "foo"]);
+        assert!(fs::read_to_string(sysutils::in_root(&root, APK_INSTALLED))
+            .unwrap()
+            .contains("P:foo\nV:1.0-r0\nF:usr/bin\nR:foo\n"));
+        assert_eq!(fs::read_to_string(sysutils::in_root(&root, APK_WORLD)).unwrap(), "bar\nfoo>=1.0\nfoo@edge\n");
+
+        // Backups are dropped, once restored
+        assert!(!Path::new(&sysutils::in_root(&root, "/lib/apk/db/limopack.bkp/foo")).exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn unknown_packages_are_refused() {
+        let root = make_root("apk-unknown");
+        let mut am = ApkMod::new(&false, &root);
+        assert_eq!(am.remove_package("baz".to_string()).unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(am.restore_package("baz".to_string()).unwrap_err().kind(), ErrorKind::NotFound);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,287 @@
+//! Parser and writer of the package manager databases in deb822 format, i.e. a plain-text
+//! list of paragraphs (stanzas) of "<Field>: <value>" lines, separated by empty lines.
+//! Such are dpkg's /var/lib/dpkg/status, apt's extended states or opkg's status.
+//! Alpine's installed database is of the same structure with letter-only field names.
+//!
+//! The document is kept as it was read: every paragraph with its comments and all the
+//! separators between paragraphs are preserved byte-for-byte (including CRLF line endings),
+//! so writing it back changes only paragraphs, those were explicitly removed or added.
+
+use std::{fmt::Display, fs, io::Error};
+
+use crate::sysutils;
+
+use colored::Colorize;
+
+#[derive(Debug, Clone)]
+enum Chunk {
+    // Raw paragraph with its fields, comments and line endings
+    Paragraph(String),
+
+    // Empty lines between paragraphs
+    Gap(String),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Deb822 {
+    chunks: Vec<Chunk>,
+}
+
+impl Deb822 {
+    /// Parse a document
+    pub fn parse(data: &str) -> Self {
+        let mut chunks: Vec<Chunk> = vec![];
+        for line in data.split_inclusive('\n') {
+            let is_gap = line.trim().is_empty();
+            match chunks.last_mut() {
+                Some(Chunk::Gap(gap)) if is_gap => gap.push_str(line),
+                Some(Chunk::Paragraph(p)) if !is_gap => p.push_str(line),
+                _ => chunks.push(if is_gap { Chunk::Gap(line.to_string()) } else { Chunk::Paragraph(line.to_string()) }),
+            }
+        }
+
+        Deb822 { chunks }
+    }
+
+    /// Load a document from the database file.
+    /// Missing or unreadable database results to an empty document.
+    pub fn load(path: &str) -> Self {
+        match fs::read_to_string(path) {
+            Ok(data) => Deb822::parse(&data),
+            Err(_) => Deb822::default(),
+        }
+    }
+
+    /// Get all raw paragraphs
+    pub fn paragraphs(&self) -> Vec<&str> {
+        self.chunks
+            .iter()
+            .filter_map(|c| match c {
+                Chunk::Paragraph(p) => Some(p.as_str()),
+                Chunk::Gap(_) => None,
+            })
+            .collect()
+    }
+
+    /// Get values of the field from all paragraphs, those have it
+    pub fn get_field_values(&self, field: &str) -> Vec<String> {
+        self.paragraphs().iter().filter_map(|p| get_field(p, field)).collect()
+    }
+
+    /// Remove all paragraphs, matching the filter. A separator after each removed paragraph
+    /// (or before it, if it is the last one) is also removed, keeping the rest as is.
+    /// Returns removed raw paragraphs.
+    pub fn remove_where<F: Fn(&str) -> bool>(&mut self, filter: F) -> Vec<String> {
+        let mut removed: Vec<String> = vec![];
+        let mut idx = 0;
+        while idx < self.chunks.len() {
+            let p = match &self.chunks[idx] {
+                Chunk::Paragraph(p) if filter(p) => p.to_owned(),
+                _ => {
+                    idx += 1;
+                    continue;
+                }
+            };
+
+            self.chunks.remove(idx);
+            if let Some(Chunk::Gap(_)) = self.chunks.get(idx) {
+                self.chunks.remove(idx);
+            } else if idx > 0 {
+                if let Some(Chunk::Gap(_)) = self.chunks.get(idx - 1) {
+                    self.chunks.remove(idx - 1);
+                    idx -= 1;
+                }
+            }
+
+            removed.push(p);
+        }
+
+        removed
+    }
+
+    /// Add a raw paragraph after the last one, keeping the trailing empty lines at the end.
+    pub fn insert(&mut self, data: &str) {
+        let mut idx = self.chunks.len();
+        if let Some(Chunk::Gap(_)) = self.chunks.last() {
+            idx -= 1;
+        }
+
+        if idx > 0 {
+            if let Chunk::Paragraph(p) = &mut self.chunks[idx - 1] {
+                if !p.ends_with('\n') {
+                    p.push('\n');
+                }
+                self.chunks.insert(idx, Chunk::Gap("\n".to_string()));
+                idx += 1;
+            }
+        }
+
+        self.chunks.insert(idx, Chunk::Paragraph(format!("{}\n", data.trim_end())));
+    }
+}
+
+impl Display for Deb822 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for c in &self.chunks {
+            match c {
+                Chunk::Paragraph(data) | Chunk::Gap(data) => write!(f, "{}", data)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Get a value of the field from a raw paragraph, if any.
+/// Multi-line values are joined with their continuation lines.
+pub fn get_field(paragraph: &str, field: &str) -> Option<String> {
+    let mut value: Option<String> = None;
+    for line in paragraph.lines().map(|l| l.trim_end_matches('\r')) {
+        if line.starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with([' ', '\t']) {
+            // Continuation of the value
+            if let Some(value) = value.as_mut() {
+                value.push('\n');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+
+        if value.is_some() {
+            break;
+        }
+
+        if let Some((key, data)) = line.split_once(':') {
+            if key.trim() == field {
+                value = Some(data.trim().to_string());
+            }
+        }
+    }
+
+    value
+}
+
+/// Write a database file atomically, backing it up to "<path>.limopack.bkp" before
+/// modification.
+pub fn write_with_backup(path: &str, data: &str, debug: &bool) -> Result<(), Error> {
+    write_with_backup_to(path, data, &format!("{}.limopack.bkp", path), debug)
+}
+
+/// Same as `write_with_backup`, except the backup is kept at the specified path.
+pub fn write_with_backup_to(path: &str, data: &str, backup_path: &str, debug: &bool) -> Result<(), Error> {
+    if *debug {
+        log::debug!("Backing up \"{}\" to \"{}\" before modification", path.bright_yellow(), backup_path.bright_yellow());
+    }
+
+    fs::copy(path, backup_path)?;
+
+    // The original is replaced only after the data is completely written,
+    // so there is nothing to restore, if writing has failed.
+    if let Err(err) = sysutils::write_atomic(path, data) {
+        log::error!("Unable to write \"{}\": \"{}\"", path.bright_yellow(), err.to_string().bright_red());
+        if *debug {
+            log::debug!("Removing backup at \"{}\"", backup_path.bright_yellow());
+        }
+
+        fs::remove_file(backup_path)?;
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_field, Deb822};
+
+    static STATUS: &str = "Package: foo\nStatus: install ok installed\nDescription: Foo\n Long description\n .\n of foo\n\n\
+                           Package: bar\nStatus: install ok installed\n\n\nPackage: baz\nStatus: deinstall ok config-files\n";
+
+    #[test]
+    fn round_trip_is_identical() {
+        for data in
+            [STATUS, "", "\n\n", "Package: foo\n", "Package: foo", "\nPackage: foo\n\n\n", "Package: foo\n\n  \nPackage: bar\n"]
+        {
+            assert_eq!(Deb822::parse(data).to_string(), data);
+        }
+    }
+
+    #[test]
+    fn round_trip_keeps_crlf() {
+        let data = "Package: foo\r\nStatus: install ok installed\r\n\r\nPackage: bar\r\n";
+        let doc = Deb822::parse(data);
+        assert_eq!(doc.to_string(), data);
+        assert_eq!(doc.paragraphs().len(), 2);
+        assert_eq!(get_field(doc.paragraphs()[0], "Status").as_deref(), Some("install ok installed"));
+    }
+
+    #[test]
+    fn paragraphs_are_split_by_empty_lines() {
+        let doc = Deb822::parse(STATUS);
+        assert_eq!(doc.get_field_values("Package"), vec!["foo", "bar", "baz"]);
+    }
+
+    #[test]
+    fn get_field_skips_comments_and_joins_continuation() {
+        let p = "# Package: commented\nPackage: foo\nDescription: Foo\n Long description\n# comment\n .\nVersion: 1.0\n";
+        assert_eq!(get_field(p, "Package").as_deref(), Some("foo"));
+        assert_eq!(get_field(p, "Description").as_deref(), Some("Foo\nLong description\n."));
+        assert_eq!(get_field(p, "Version").as_deref(), Some("1.0"));
+        assert_eq!(get_field(p, "Status"), None);
+    }
+
+    #[test]
+    fn remove_where_removes_all_matching() {
+        let mut doc = Deb822::parse(STATUS);
+        let removed = doc.remove_where(|p| get_field(p, "Status").unwrap_or_default().ends_with(" installed"));
+        assert_eq!(removed.len(), 2);
+        assert!(removed[0].starts_with("Package: foo\n"));
+        assert_eq!(doc.to_string(), "Package: baz\nStatus: deinstall ok config-files\n");
+    }
+
+    #[test]
+    fn remove_where_keeps_other_separators() {
+        let mut doc = Deb822::parse(STATUS);
+        doc.remove_where(|p| get_field(p, "Package").as_deref() == Some("bar"));
+        assert_eq!(doc.get_field_values("Package"), vec!["foo", "baz"]);
+        assert_eq!(doc.to_string(), STATUS.replace("Package: bar\nStatus: install ok installed\n\n\n", ""));
+
+        // The last paragraph takes the separator before it
+        let mut doc = Deb822::parse("Package: foo\n\nPackage: bar\n");
+        doc.remove_where(|p| get_field(p, "Package").as_deref() == Some("bar"));
+        assert_eq!(doc.to_string(), "Package: foo\n");
+
+        // Nothing matches, nothing changes
+        let mut doc = Deb822::parse(STATUS);
+        assert!(doc.remove_where(|_| false).is_empty());
+        assert_eq!(doc.to_string(), STATUS);
+    }
+
+    #[test]
+    fn insert_keeps_trailing_newlines() {
+        let mut doc = Deb822::parse("Package: foo\n\n");
+        doc.insert("Package: bar\n\n\n");
+        assert_eq!(doc.to_string(), "Package: foo\n\nPackage: bar\n\n");
+
+        // Missing trailing newline of the last paragraph is added
+        let mut doc = Deb822::parse("Package: foo");
+        doc.insert("Package: bar");
+        assert_eq!(doc.to_string(), "Package: foo\n\nPackage: bar\n");
+
+        let mut doc = Deb822::default();
+        doc.insert("Package: bar");
+        assert_eq!(doc.to_string(), "Package: bar\n");
+    }
+
+    #[test]
+    fn remove_and_insert_round_trip() {
+        let data = "Package: foo\nStatus: install ok installed\n\nPackage: bar\nStatus: install ok installed\n";
+        let mut doc = Deb822::parse(data);
+        let removed = doc.remove_where(|p| get_field(p, "Package").as_deref() == Some("bar"));
+        doc.insert(&removed[0]);
+        assert_eq!(doc.to_string(), data);
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    fmt::Display,
+    io::{Error, ErrorKind},
+    path::Path,
+};
+
+use super::{
+    apkmod::{self, ApkMod},
+    dpkglock::DpkgLock,
+    dpkgmod::{self, DpkgMod},
+    opkgmod::{self, OpkgMod},
+    pacmanmod::{self, PacmanLock, PacmanMod},
+    rmpak::PackMod,
+};
+use crate::sysutils;
+
+/// Supported package managers.
+/// The order of the variants is also a priority order, if more than one
+/// package manager database is found on the system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PkgManager {
+    Dpkg,
+    Opkg,
+    Apk,
+    Pacman,
+}
+
+/// Held lock of a package manager database, which is released when this is dropped
+pub enum PkgLock {
+    Dpkg { _lock: DpkgLock },
+    Pacman { _lock: PacmanLock },
+}
+
+pub static PKG_MANAGERS: [PkgManager; 4] = [PkgManager::Dpkg, PkgManager::Opkg, PkgManager::Apk, PkgManager::Pacman];
+
+impl PkgManager {
+    /// Get package manager by its name, as it is passed from the CLI
+    pub fn from_name(name: &str) -> Result<Self, Error> {
+        for pm in PKG_MANAGERS {
+            if pm.name() == name {
+                return Ok(pm);
+            }
+        }
+
+        Err(Error::new(ErrorKind::InvalidInput, format!("Unsupported package manager: {}", name)))
+    }
+
+    /// Name of the package manager
+    pub fn name(&self) -> &'static str {
+        match self {
+            PkgManager::Dpkg => "dpkg",
+            PkgManager::Opkg => "opkg",
+            PkgManager::Apk => "apk",
+            PkgManager::Pacman => "pacman",
+        }
+    }
+
+    /// Path to the package manager database within the root, which presence denotes
+    /// the package manager is active on the system.
+    fn get_db_path(&self, root: &Path) -> String {
+        sysutils::in_root(
+            root,
+            match self {
+                PkgManager::Dpkg => dpkgmod::DPKG_STATUS,
+                PkgManager::Opkg => opkgmod::OPKG_STATUS,
+                PkgManager::Apk => apkmod::APK_INSTALLED,
+                PkgManager::Pacman => pacmanmod::PACMAN_LOCAL_D,
+            },
+        )
+    }
+
+    /// Returns true if the package manager database is found on the system in the root
+    pub fn is_present(&self, root: &Path) -> bool {
+        Path::new(&self.get_db_path(root)).exists()
+    }
+
+    /// Lock the package manager database, if the package manager supports it. The lock
+    /// is taken before the database is loaded and is held until it is saved, so it is
+    /// released when the returned lock is dropped.
+    pub fn lock(&self, debug: &bool, root: &Path, wait: bool) -> Result<Option<PkgLock>, Error> {
+        match self {
+            PkgManager::Dpkg => Ok(Some(PkgLock::Dpkg { _lock: DpkgLock::acquire(debug, root, wait)? })),
+            PkgManager::Pacman => Ok(Some(PkgLock::Pacman { _lock: PacmanLock::acquire(debug, root, wait)? })),
+            _ => Ok(None),
+        }
+    }
+
+    /// Get an implementation of a package database modifier, which loads the database in the root
+    pub fn get_packmod<'a>(&self, debug: &'a bool, root: &Path) -> Box<dyn PackMod + 'a> {
+        match self {
+            PkgManager::Dpkg => Box::new(DpkgMod::new(debug, root)),
+            PkgManager::Opkg => Box::new(OpkgMod::new(debug, root)),
+            PkgManager::Apk => Box::new(ApkMod::new(debug, root)),
+            PkgManager::Pacman => Box::new(PacmanMod::new(debug, root)),
+        }
+    }
+}
+
+impl Display for PkgManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Get a root directory of the system, where package manager databases are, "/" by default
+pub fn get_root(root: Option<&String>) -> &Path {
+    Path::new(root.map(|r| r.as_str()).unwrap_or("/"))
+}
+
+/// Find all package managers, those databases are present on the system in the root
+pub fn detect(root: &Path) -> Vec<PkgManager> {
+    PKG_MANAGERS.iter().filter(|pm| pm.is_present(root)).copied().collect()
+}
+
+/// Select a package manager to operate on. If the package manager is explicitly specified,
+/// it still should be present on the system. Otherwise it is detected, and if there are
+/// more than one found, the first one is taken by the priority. Databases are looked up
+/// in the root directory of the system, which is "/" unless an image is provisioned.
+pub fn select(root: &Path, name: Option<&String>) -> Result<PkgManager, Error> {
+    if let Some(name) = name {
+        let pm = PkgManager::from_name(name)?;
+        if !pm.is_present(root) {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Database of the package manager \"{}\" was not found at {}", pm, pm.get_db_path(root)),
+            ));
+        }
+        return Ok(pm);
+    }
+
+    let pms = detect(root);
+    match pms.first() {
+        Some(pm) => {
+            if pms.len() > 1 {
+                log::warn!(
+                    "Found several package managers: {}. Using \"{}\", which can be changed with --pm option",
+                    pms.iter().map(|p| p.name()).collect::<Vec<&str>>().join(", "),
+                    pm
+                );
+            }
+            Ok(*pm)
+        }
+        None => Err(Error::new(
+            ErrorKind::NotFound,
+            format!(
+                "No supported package manager database found on the system in {} (supported: {})",
+                root.to_str().unwrap(),
+                PKG_MANAGERS.iter().map(|p| p.name()).collect::<Vec<&str>>().join(", ")
+            ),
+        )),
+    }
+}
@@ -0,0 +1,66 @@
+//! Locking of the dpkg database. dpkg holds "lock" while it is modifying the database,
+//! and frontends (apt etc) are holding "lock-frontend" for the whole transaction.
+//! Outside of a transaction both are taken in the same order as apt does, so no other
+//! frontend or dpkg itself can run meanwhile. They are taken before the database is read
+//! and held until it is written back, so no changes of others are overwritten.
+//!
+//! Within a transaction (i.e. limopack is called from a maintainer script), both locks are
+//! already held by the running dpkg and its frontend, and the status database will be
+//! rewritten by dpkg afterwards from its own state, so it must not be modified at all.
+
+use std::{
+    env,
+    fs::File,
+    io::{Error, ErrorKind},
+    path::Path,
+};
+
+use colored::Colorize;
+
+use crate::sysutils;
+
+pub static DPKG_LOCK: &str = "/var/lib/dpkg/lock";
+pub static DPKG_LOCK_FRONTEND: &str = "/var/lib/dpkg/lock-frontend";
+
+/// Returns true if running inside a dpkg transaction, e.g. from a maintainer script.
+/// dpkg sets DPKG_RUNNING_VERSION to the environment of the scripts it runs.
+pub fn in_transaction() -> bool {
+    env::var_os("DPKG_RUNNING_VERSION").is_some()
+}
+
+/// Returns an error if running inside a dpkg transaction
+pub fn check_transaction() -> Result<(), Error> {
+    if in_transaction() {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "Refusing to modify the dpkg database within a running dpkg transaction",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Held dpkg locks. Locks are released when this is dropped.
+pub struct DpkgLock {
+    _locks: Vec<File>,
+}
+
+impl DpkgLock {
+    /// Take frontend and database locks. If `wait` is false and the database is locked,
+    /// then an error is returned immediately. Within a transaction the database must not
+    /// be modified, so this is an error as well.
+    pub fn acquire(debug: &bool, root: &Path, wait: bool) -> Result<Self, Error> {
+        check_transaction()?;
+
+        let mut locks: Vec<File> = vec![];
+        for path in [DPKG_LOCK_FRONTEND, DPKG_LOCK] {
+            let path = sysutils::in_root(root, path);
+            if *debug {
+                log::debug!("Locking \"{}\"", path.bright_yellow());
+            }
+            locks.push(sysutils::lock_file(&path, wait)?);
+        }
+
+        Ok(DpkgLock { _locks: locks })
+    }
+}
@@ -1,8 +1,10 @@
 use std::{
-    fs::{self, OpenOptions},
-    io::{Error, ErrorKind, Write},
+    fs,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
 };
 
+use chrono::Local;
 use colored::Colorize;
 
 /// This module is designed to remove a package from /var/lib/dpkg/status from being
@@ -10,42 +12,308 @@ use colored::Colorize;
 /// keep its data on a media, modify its content to further reuse and allow it to be
 /// updated by a standard package manager means.
 ///
-use super::rmpak::PackMod;
+/// Besides the status, the package is also taken away from everywhere else dpkg and apt
+/// are keeping it: /var/lib/dpkg/info/<pkg>.* (file lists, md5sums, maintainer scripts,
+/// triggers etc), trigger interests in /var/lib/dpkg/triggers and apt's extended states.
+///
+/// Each save is backed up to its own version directory /var/lib/dpkg/limopack.bkp/<timestamp>
+/// as follows:
+///
+///     status, extended_states, triggers/*  - databases as they were before modification
+///     info/*                               - info files of removed packages
+///     packages/<name>:<arch>/              - what was removed per a package, so it can be
+///                                            registered back again:
+///         status                           - stanza of the package
+///         extended_states                  - apt's extended states stanza, if any
+///         triggers                         - trigger interests as "<trigger file>\t<line>"
+///
+/// The database is not locked here: the caller holds dpkg locks (see `DpkgLock`) from
+/// before it is loaded until it is saved.
+///
+use super::{
+    deb822::{self, Deb822},
+    dpkglock,
+    rmpak::PackMod,
+};
+use crate::sysutils;
+
+pub static DPKG_STATUS: &str = "/var/lib/dpkg/status";
+pub static DPKG_INFO_D: &str = "/var/lib/dpkg/info";
+pub static DPKG_TRIGGERS_D: &str = "/var/lib/dpkg/triggers";
+pub static DPKG_BACKUP_D: &str = "/var/lib/dpkg/limopack.bkp";
+pub static APT_EXT_STATES: &str = "/var/lib/apt/extended_states";
+
+/// Returns true if a package in the status stanza is installed on the system.
+/// The "Status:" field is "<want> <flag> <status>", e.g. "install ok installed".
+/// Unpacked or half-configured packages are still considered as installed,
+/// because they are often in these states, while being set up by a running dpkg.
+pub fn is_installed(data: &str) -> bool {
+    match deb822::get_field(data, "Status") {
+        Some(status) => matches!(
+            status.split_whitespace().nth(2).unwrap_or_default(),
+            "installed" | "triggers-pending" | "triggers-awaited" | "unpacked" | "half-configured"
+        ),
+        None => false,
+    }
+}
+
+/// Check if a package in the status stanza can be unregistered
+pub fn check_status(pn: &str, data: &str) -> Result<(), Error> {
+    if !is_installed(data) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Package \"{}\" is not installed (status: {})",
+                pn.bright_yellow(),
+                deb822::get_field(data, "Status").unwrap_or_else(|| "unknown".to_string())
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// A package, removed from the database
+#[derive(Clone)]
+struct DpkgPackage {
+    name: String,
+    arch: String,
+    status: String,
+    ext_states: Vec<String>,
+}
+
+impl DpkgPackage {
+    /// Returns true if a package reference, such as "<name>" or "<name>:<arch>" is mentioning the package.
+    fn is_ref(&self, pref: &str) -> bool {
+        pref == self.name || pref == self.get_ref()
+    }
+
+    /// Get fully qualified package reference as "<name>:<arch>"
+    fn get_ref(&self) -> String {
+        format!("{}:{}", self.name, self.arch)
+    }
+}
 
 #[derive(Clone)]
 pub struct DpkgMod<'a> {
-    packages: Vec<String>,
+    packages: Deb822,
+    ext_states: Deb822,
+    removed: Vec<DpkgPackage>,
+
+    // Backup directories of the packages that are registered back
+    restored: Vec<PathBuf>,
     status_path: String,
+    info_path: String,
+    triggers_path: String,
+    ext_states_path: String,
+    backup_path: String,
     debug: &'a bool,
 }
 
 impl<'a> DpkgMod<'a> {
-    pub fn new(debug: &'a bool) -> Self {
-        DpkgMod { packages: vec![], status_path: "/var/lib/dpkg/status".to_string(), debug }.load()
-    }
-
-    /// Remove field from a string.
-    /// Fields are first keywords, following ":" colon.
-    fn chop_field(&self, line: String) -> String {
-        match line.split_once(':') {
-            Some(data) => data.1.trim().to_string(),
-            None => String::from(""),
+    pub fn new(debug: &'a bool, root: &Path) -> Self {
+        DpkgMod {
+            packages: Deb822::default(),
+            ext_states: Deb822::default(),
+            removed: vec![],
+            restored: vec![],
+            status_path: sysutils::in_root(root, DPKG_STATUS),
+            info_path: sysutils::in_root(root, DPKG_INFO_D),
+            triggers_path: sysutils::in_root(root, DPKG_TRIGGERS_D),
+            ext_states_path: sysutils::in_root(root, APT_EXT_STATES),
+            backup_path: sysutils::in_root(root, DPKG_BACKUP_D),
+            debug,
         }
+        .load()
     }
 
     /// Load package status
     fn load(&mut self) -> Self {
-        if let Ok(data) = fs::read_to_string(&self.status_path) {
-            let _ = &self.packages.extend(data.split("\n\n").map(|el| el.to_string()).collect::<Vec<String>>());
+        self.packages = Deb822::load(&self.status_path);
+        self.ext_states = Deb822::load(&self.ext_states_path);
+        self.to_owned()
+    }
+
+    /// Split multi-arch package name "<name>:<arch>" to its name and optional architecture
+    fn split_arch(pn: &str) -> (String, Option<String>) {
+        match pn.split_once(':') {
+            Some((name, arch)) => (name.to_string(), Some(arch.to_string())),
+            None => (pn.to_string(), None),
         }
+    }
 
-        self.to_owned()
+    /// Returns true if a stanza is of a package with the name and the architecture, if it is specified
+    fn is_package_arch(name: &str, arch: &Option<String>, data: &str) -> bool {
+        deb822::get_field(data, "Package").as_deref() == Some(name)
+            && (arch.is_none() || deb822::get_field(data, "Architecture") == *arch)
+    }
+
+    /// Returns true if a package reference, such as "<name>" or "<name>:<arch>" is
+    /// mentioning any of removed packages.
+    fn is_removed(&self, pref: &str) -> bool {
+        self.removed.iter().any(|p| p.is_ref(pref))
+    }
+
+    /// Find the latest backup of a removed package
+    fn find_backup(&self, name: &str, arch: &Option<String>) -> Option<PathBuf> {
+        let mut versions: Vec<PathBuf> = fs::read_dir(&self.backup_path).ok()?.flatten().map(|e| e.path()).collect();
+        versions.sort();
+
+        for v in versions.iter().rev() {
+            for e in fs::read_dir(v.join("packages")).into_iter().flatten().flatten() {
+                let pref = e.file_name().to_str().unwrap_or_default().to_string();
+                let (p_name, p_arch) = DpkgMod::split_arch(&pref);
+                if p_name == name && (arch.is_none() || p_arch == *arch) {
+                    return Some(e.path());
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Move all info files of removed packages into a backup directory.
+    /// Info files are named "<name>.<type>" or "<name>:<arch>.<type>" for multi-arch packages.
+    fn backup_info(&self, bkp_path: &Path) -> Result<(), Error> {
+        let bkp_path = bkp_path.join("info");
+        for e in fs::read_dir(&self.info_path)?.flatten() {
+            let fname = e.file_name().to_str().unwrap_or_default().to_string();
+            match fname.rsplit_once('.') {
+                Some((pref, _)) if self.is_removed(pref) => {}
+                _ => continue,
+            }
+
+            if *self.debug {
+                log::debug!("Moving \"{}\" to \"{}\"", fname.bright_yellow(), bkp_path.to_str().unwrap().bright_yellow());
+            }
+
+            fs::create_dir_all(&bkp_path)?;
+            fs::rename(e.path(), bkp_path.join(&fname))?;
+        }
+
+        Ok(())
+    }
+
+    /// Copy info files of restored packages back from their backup
+    fn restore_info(&self) -> Result<(), Error> {
+        for pkg_path in &self.restored {
+            let pref = pkg_path.file_name().unwrap().to_str().unwrap().to_string();
+            let name = DpkgMod::split_arch(&pref).0;
+            let info_bkp = pkg_path.parent().unwrap().parent().unwrap().join("info");
+
+            for e in fs::read_dir(info_bkp).into_iter().flatten().flatten() {
+                let fname = e.file_name().to_str().unwrap_or_default().to_string();
+                match fname.rsplit_once('.') {
+                    Some((f_pref, _)) if f_pref == name || f_pref == pref => {}
+                    _ => continue,
+                }
+
+                if *self.debug {
+                    log::debug!("Restoring \"{}\" to \"{}\"", fname.bright_yellow(), self.info_path.bright_yellow());
+                }
+                fs::copy(e.path(), Path::new(&self.info_path).join(&fname))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Backup a trigger interests file, unless it is already backed up
+    fn backup_trigger(&self, t_path: &Path, bkp_path: &Path) -> Result<(), Error> {
+        let bkp_path = bkp_path.join("triggers");
+        let dst = bkp_path.join(t_path.file_name().unwrap());
+        if t_path.exists() && !dst.exists() {
+            fs::create_dir_all(&bkp_path)?;
+            fs::copy(t_path, dst)?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove removed packages from the trigger interests. These are "File" with lines as
+    /// "<path> <package>[/noawait]" and files per a trigger name, with lines as "<package>[/noawait]".
+    ///
+    /// Returns removed interests as a package reference, trigger file name and the line.
+    fn update_triggers(&self, bkp_path: &Path) -> Result<Vec<(String, String, String)>, Error> {
+        let mut removed: Vec<(String, String, String)> = vec![];
+        let t_path = Path::new(&self.triggers_path);
+        if !t_path.is_dir() {
+            return Ok(removed);
+        }
+
+        for e in fs::read_dir(t_path)?.flatten() {
+            let fname = e.file_name().to_str().unwrap_or_default().to_string();
+            if !e.path().is_file() || ["Lock", "Unincorp"].contains(&fname.as_str()) {
+                continue;
+            }
+
+            let data = fs::read_to_string(e.path())?;
+            let mut buff: Vec<&str> = vec![];
+            for line in data.lines() {
+                let pref = line.split_whitespace().last().unwrap_or_default().split('/').next().unwrap_or_default();
+                match self.removed.iter().find(|p| p.is_ref(pref)) {
+                    Some(pkg) => removed.push((pkg.get_ref(), fname.to_owned(), line.to_string())),
+                    None => buff.push(line),
+                }
+            }
+
+            if buff.len() != data.lines().count() {
+                log::info!("Removing trigger interests from \"{}\"", fname.bright_yellow());
+                self.backup_trigger(&e.path(), bkp_path)?;
+                sysutils::write_atomic(e.path().to_str().unwrap(), &buff.iter().map(|l| format!("{}\n", l)).collect::<String>())?;
+            }
+        }
+
+        Ok(removed)
     }
 
-    /// Returns true if a current chunk corresponds to a given package name
-    fn is_package(&self, name: String, data: String) -> bool {
-        let dls: Vec<String> = data.split('\n').map(|x| x.to_string()).collect();
-        name == self.chop_field(dls[0].to_owned())
+    /// Add trigger interests of restored packages back
+    fn restore_triggers(&self, bkp_path: &Path) -> Result<(), Error> {
+        for pkg_path in &self.restored {
+            let data = match fs::read_to_string(pkg_path.join("triggers")) {
+                Ok(data) => data,
+                Err(_) => continue,
+            };
+
+            for (fname, line) in data.lines().filter_map(|l| l.split_once('\t')) {
+                let t_path = Path::new(&self.triggers_path).join(fname);
+                let mut t_data = fs::read_to_string(&t_path).unwrap_or_default();
+                if t_data.lines().any(|l| l == line) {
+                    continue;
+                }
+
+                log::info!("Restoring trigger interests to \"{}\"", fname.bright_yellow());
+                self.backup_trigger(&t_path, bkp_path)?;
+                t_data.push_str(&format!("{}\n", line));
+                sysutils::write_atomic(t_path.to_str().unwrap(), &t_data)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Keep what was removed per a package, so it can be registered back.
+    fn backup_packages(&self, bkp_path: &Path, triggers: &[(String, String, String)]) -> Result<(), Error> {
+        for pkg in &self.removed {
+            let pkg_path = bkp_path.join("packages").join(pkg.get_ref());
+            fs::create_dir_all(&pkg_path)?;
+            fs::write(pkg_path.join("status"), &pkg.status)?;
+
+            if !pkg.ext_states.is_empty() {
+                fs::write(pkg_path.join("extended_states"), pkg.ext_states.join("\n"))?;
+            }
+
+            let p_triggers: Vec<String> = triggers
+                .iter()
+                .filter(|(pref, _, _)| *pref == pkg.get_ref())
+                .map(|(_, fname, line)| format!("{}\t{}\n", fname, line))
+                .collect();
+            if !p_triggers.is_empty() {
+                fs::write(pkg_path.join("triggers"), p_triggers.concat())?;
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -53,68 +321,102 @@ impl PackMod for DpkgMod<'_> {
     /// Remove package from the index. This still keeps only the state of the modpack,
     /// but does not writes anything to the disk.
     fn remove_package(&mut self, pn: String) -> Result<(), Error> {
-        let mut buff: Vec<String> = vec![];
         log::info!("Looking for \"{}\" package...", pn.bright_yellow());
-        let mut found = false;
-        for p in &self.packages {
-            if !self.is_package(pn.to_owned(), p.to_owned()) {
-                buff.push(p.to_owned());
-            } else {
-                log::info!("Altering package manager database for \"{}\"", pn.bright_yellow());
-                found = true;
-            }
-        }
-
-        self.packages = Vec::new();
-        self.packages.extend(buff);
+        let (name, arch) = DpkgMod::split_arch(&pn);
 
-        if !found {
+        let found: Vec<&str> =
+            self.packages.paragraphs().into_iter().filter(|p| DpkgMod::is_package_arch(&name, &arch, p)).collect();
+        if found.is_empty() {
             return Err(Error::new(
                 ErrorKind::NotFound,
                 format!("Package \"{}\" was not found in the database", pn.bright_yellow()),
             ));
         }
 
+        for p in &found {
+            check_status(&pn, p)?;
+        }
+
+        log::info!("Altering package manager database for \"{}\"", pn.bright_yellow());
+        for p in self.packages.remove_where(|p| DpkgMod::is_package_arch(&name, &arch, p)) {
+            let p_arch = deb822::get_field(&p, "Architecture").unwrap_or_default();
+            let ext_states = self.ext_states.remove_where(|s| DpkgMod::is_package_arch(&name, &Some(p_arch.to_owned()), s));
+            self.removed.push(DpkgPackage { name: name.to_owned(), arch: p_arch, status: p, ext_states });
+        }
+
         Ok(())
     }
 
+    /// Register back a package, which was previously removed from the index.
+    /// It is taken from the latest backup, which contains it.
+    fn restore_package(&mut self, pn: String) -> Result<(), Error> {
+        log::info!("Looking for \"{}\" package in backups...", pn.bright_yellow());
+        let (name, arch) = DpkgMod::split_arch(&pn);
+
+        if self.packages.paragraphs().iter().any(|p| DpkgMod::is_package_arch(&name, &arch, p)) {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("Package \"{}\" is already registered in the database", pn.bright_yellow()),
+            ));
+        }
+
+        let pkg_path = match self.find_backup(&name, &arch) {
+            Some(pkg_path) => pkg_path,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("Package \"{}\" was not found in backups at {}", pn.bright_yellow(), self.backup_path),
+                ))
+            }
+        };
+
+        log::info!("Restoring \"{}\" from {}", pn.bright_yellow(), pkg_path.to_str().unwrap().bright_yellow());
+        self.packages.insert(&fs::read_to_string(pkg_path.join("status"))?);
+        if let Ok(data) = fs::read_to_string(pkg_path.join("extended_states")) {
+            self.ext_states.insert(&data);
+        }
+        self.restored.push(pkg_path);
+
+        Ok(())
+    }
+
+    /// Get names of all packages in the database.
+    fn list_packages(&self) -> Vec<String> {
+        self.packages.paragraphs().iter().filter(|p| is_installed(p)).filter_map(|p| deb822::get_field(p, "Package")).collect()
+    }
+
     /// Save the current state to the disk.
     fn save(&self) -> Result<(), Error> {
         log::info!("Save changes to the dpkg database");
+        dpkglock::check_transaction()?;
+
+        let bkp_path = PathBuf::from(&self.backup_path).join(Local::now().format("%Y%m%d%H%M%S%3f").to_string());
+        fs::create_dir_all(&bkp_path)?;
         if *self.debug {
-            log::debug!("Backing up \"{}\" before modification", self.status_path.to_owned().bright_yellow());
-        }
-
-        let status_backup_path = format!("{}.limopack.bkp", &self.status_path);
-        fs::copy(&self.status_path, &status_backup_path)?;
-
-        if let Ok(mut fptr) = OpenOptions::new().create(true).write(true).truncate(true).open(&self.status_path) {
-            let p_idx = self.packages.len() - 1;
-            for (idx, pinfo) in self.packages.iter().enumerate() {
-                match fptr.write_all(format!("{}{}", pinfo, if idx < p_idx { "\n\n" } else { "" }).as_bytes()) {
-                    Ok(_) => {}
-                    Err(err) => {
-                        log::error!(
-                            "Unable to write \"{}\": \"{}\"",
-                            &self.status_path.bright_yellow(),
-                            err.to_string().bright_red()
-                        );
-                        // Badaboom. We probably screwed it all up and now need to restore the backup. Hopefully.
-                        if *self.debug {
-                            log::debug!("Restoring \"{}\"", self.status_path.to_owned().bright_yellow());
-                        }
-                        fs::copy(&status_backup_path, &self.status_path)?;
-
-                        if *self.debug {
-                            log::debug!("Removing backup at \"{}\"", &status_backup_path.to_owned().bright_yellow());
-                        }
-
-                        fs::remove_file(&status_backup_path)?;
-                    }
-                }
-            }
+            log::debug!("Backup is at \"{}\"", bkp_path.to_str().unwrap().bright_yellow());
         }
 
-        Ok(())
+        deb822::write_with_backup_to(
+            &self.status_path,
+            &self.packages.to_string(),
+            bkp_path.join("status").to_str().unwrap(),
+            self.debug,
+        )?;
+
+        if Path::new(&self.ext_states_path).exists() {
+            log::info!("Save changes to the apt extended states");
+            deb822::write_with_backup_to(
+                &self.ext_states_path,
+                &self.ext_states.to_string(),
+                bkp_path.join("extended_states").to_str().unwrap(),
+                self.debug,
+            )?;
+        }
+
+        let triggers = self.update_triggers(&bkp_path)?;
+        self.restore_triggers(&bkp_path)?;
+        self.backup_info(&bkp_path)?;
+        self.restore_info()?;
+        self.backup_packages(&bkp_path, &triggers)
     }
 }
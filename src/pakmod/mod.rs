@@ -1,2 +1,8 @@
+pub mod apkmod;
+pub mod deb822;
+pub mod detect;
+pub mod dpkglock;
 pub mod dpkgmod;
+pub mod opkgmod;
+pub mod pacmanmod;
 pub mod rmpak;
@@ -0,0 +1,274 @@
+use std::{
+    fs,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use colored::Colorize;
+
+/// This module removes a package from /usr/lib/opkg/status, which is used on
+/// OpenWrt/Yocto images. The format of the status is almost the same as dpkg's,
+/// but opkg additionally keeps a per-package control data in /usr/lib/opkg/info/<pkg>.*
+/// which also needs to be taken away, so the package looks as never installed.
+///
+/// Info files of a removed package are kept in /usr/lib/opkg/limopack.bkp/ together
+/// with its status stanza as "<pkg>.status", so it can be registered back again.
+///
+use super::{
+    deb822::{self, Deb822},
+    dpkgmod,
+    rmpak::PackMod,
+};
+use crate::sysutils;
+
+pub static OPKG_STATUS: &str = "/usr/lib/opkg/status";
+pub static OPKG_INFO_D: &str = "/usr/lib/opkg/info";
+
+#[derive(Clone)]
+pub struct OpkgMod<'a> {
+    packages: Deb822,
+
+    // Removed packages with their status stanzas
+    removed: Vec<(String, Vec<String>)>,
+
+    // Packages, those are registered back
+    restored: Vec<String>,
+    status_path: String,
+    info_path: String,
+    debug: &'a bool,
+}
+
+impl<'a> OpkgMod<'a> {
+    pub fn new(debug: &'a bool, root: &Path) -> Self {
+        OpkgMod {
+            packages: Deb822::default(),
+            removed: vec![],
+            restored: vec![],
+            status_path: sysutils::in_root(root, OPKG_STATUS),
+            info_path: sysutils::in_root(root, OPKG_INFO_D),
+            debug,
+        }
+        .load()
+    }
+
+    /// Load package status
+    fn load(&mut self) -> Self {
+        self.packages = Deb822::load(&self.status_path);
+        self.to_owned()
+    }
+
+    /// Get a directory, where info files of removed packages are kept
+    fn get_backup_path(&self) -> PathBuf {
+        Path::new(&self.info_path).with_file_name("limopack.bkp")
+    }
+
+    /// Move all "<pkg>.*" info files of a package into a backup directory
+    fn backup_info(&self, pn: &str) -> Result<(), Error> {
+        let bkp_path = self.get_backup_path();
+        let prefix = format!("{}.", pn);
+        for e in fs::read_dir(&self.info_path)?.flatten() {
+            let fname = e.file_name().to_str().unwrap_or_default().to_string();
+            // Skip other packages, those are named with the same prefix, e.g. "foo.bar.list" for "foo"
+            match fname.strip_prefix(&prefix) {
+                Some(ext) if !ext.contains('.') => {}
+                _ => continue,
+            }
+
+            if *self.debug {
+                log::debug!("Moving \"{}\" to \"{}\"", fname.bright_yellow(), bkp_path.to_str().unwrap().bright_yellow());
+            }
+
+            fs::create_dir_all(&bkp_path)?;
+            fs::rename(e.path(), bkp_path.join(&fname))?;
+        }
+
+        Ok(())
+    }
+
+    /// Move info files of a restored package back from the backup directory
+    fn restore_info(&self, pn: &str) -> Result<(), Error> {
+        let bkp_path = self.get_backup_path();
+        let prefix = format!("{}.", pn);
+        for e in fs::read_dir(&bkp_path)?.flatten() {
+            let fname = e.file_name().to_str().unwrap_or_default().to_string();
+            match fname.strip_prefix(&prefix) {
+                Some("status") => {
+                    fs::remove_file(e.path())?;
+                    continue;
+                }
+                Some(ext) if !ext.contains('.') => {}
+                _ => continue,
+            }
+
+            if *self.debug {
+                log::debug!("Restoring \"{}\" to \"{}\"", fname.bright_yellow(), self.info_path.bright_yellow());
+            }
+            fs::rename(e.path(), Path::new(&self.info_path).join(&fname))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl PackMod for OpkgMod<'_> {
+    /// Remove package from the index. This still keeps only the state of the modpack,
+    /// but does not writes anything to the disk.
+    fn remove_package(&mut self, pn: String) -> Result<(), Error> {
+        log::info!("Looking for \"{}\" package...", pn.bright_yellow());
+        let is_package = |p: &str| deb822::get_field(p, "Package").as_deref() == Some(pn.as_str());
+
+        // All stanzas of the package are removed, so all of them should be installed
+        let found: Vec<&str> = self.packages.paragraphs().into_iter().filter(|p| is_package(p)).collect();
+        if found.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Package \"{}\" was not found in the database", pn.bright_yellow()),
+            ));
+        }
+
+        for p in &found {
+            dpkgmod::check_status(&pn, p)?;
+        }
+
+        let stanzas = self.packages.remove_where(is_package);
+
+        log::info!("Altering package manager database for \"{}\"", pn.bright_yellow());
+        self.removed.push((pn, stanzas));
+        Ok(())
+    }
+
+    /// Register back a package, which was previously removed from the index.
+    fn restore_package(&mut self, pn: String) -> Result<(), Error> {
+        log::info!("Looking for \"{}\" package in backups...", pn.bright_yellow());
+        if self.packages.paragraphs().iter().any(|p| deb822::get_field(p, "Package").as_deref() == Some(pn.as_str())) {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("Package \"{}\" is already registered in the database", pn.bright_yellow()),
+            ));
+        }
+
+        let st_path = self.get_backup_path().join(format!("{}.status", pn));
+        let data = match fs::read_to_string(&st_path) {
+            Ok(data) => data,
+            Err(_) => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!(
+                        "Package \"{}\" was not found in backups at {}",
+                        pn.bright_yellow(),
+                        self.get_backup_path().to_str().unwrap()
+                    ),
+                ))
+            }
+        };
+
+        log::info!("Restoring \"{}\" from {}", pn.bright_yellow(), st_path.to_str().unwrap().bright_yellow());
+        for p in Deb822::parse(&data).paragraphs() {
+            self.packages.insert(p);
+        }
+        self.restored.push(pn);
+
+        Ok(())
+    }
+
+    /// Get names of all packages in the database.
+    fn list_packages(&self) -> Vec<String> {
+        self.packages
+            .paragraphs()
+            .iter()
+            .filter(|p| dpkgmod::is_installed(p))
+            .filter_map(|p| deb822::get_field(p, "Package"))
+            .collect()
+    }
+
+    /// Save the current state to the disk.
+    fn save(&self) -> Result<(), Error> {
+        log::info!("Save changes to the opkg database");
+        deb822::write_with_backup(&self.status_path, &self.packages.to_string(), self.debug)?;
+
+        for (pn, stanzas) in &self.removed {
+            self.backup_info(pn)?;
+            fs::create_dir_all(self.get_backup_path())?;
+            fs::write(self.get_backup_path().join(format!("{}.status", pn)), stanzas.join("\n"))?;
+        }
+
+        for pn in &self.restored {
+            self.restore_info(pn)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OpkgMod, OPKG_INFO_D, OPKG_STATUS};
+    use crate::{pakmod::rmpak::PackMod, sysutils};
+    use std::{
+        env, fs,
+        io::ErrorKind,
+        path::{Path, PathBuf},
+        process,
+    };
+
+    static STATUS: &str = "Package: foo\nVersion: 1.0\nStatus: install ok installed\n\n\
+                           Package: bar\nVersion: 2.0\nStatus: install ok installed\n";
+
+    /// Create an image root with opkg status and info files of "foo" and "bar"
+    fn make_root(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("limopack-test-{}-{}", process::id(), name));
+        let info = PathBuf::from(sysutils::in_root(&root, OPKG_INFO_D));
+        fs::create_dir_all(&info).unwrap();
+        fs::write(sysutils::in_root(&root, OPKG_STATUS), STATUS).unwrap();
+        for fname in ["foo.control", "foo.list", "foo-extra.list", "bar.list"] {
+            fs::write(info.join(fname), fname).unwrap();
+        }
+
+        root
+    }
+
+    fn get_info(root: &Path) -> Vec<String> {
+        let mut out: Vec<String> = fs::read_dir(sysutils::in_root(root, OPKG_INFO_D))
+            .unwrap()
+            .flatten()
+            .map(|e| e.file_name().to_str().unwrap().to_string())
+            .collect();
+        out.sort();
+
+        out
+    }
+
+    #[test]
+    fn removed_package_is_restored() {
+        let root = make_root("opkg-restore");
+        let mut om = OpkgMod::new(&false, &root);
+        om.remove_package("foo".to_string()).unwrap();
+        om.save().unwrap();
+
+        assert_eq!(OpkgMod::new(&false, &root).list_packages(), ["bar"]);
+        assert_eq!(get_info(&root), ["bar.list", "foo-extra.list"]);
+
+        let mut om = OpkgMod::new(&false, &root);
+        om.restore_package("foo".to_string()).unwrap();
+        om.save().unwrap();
+
+        let mut packages = OpkgMod::new(&false, &root).list_packages();
+        packages.sort();
+        assert_eq!(packages, ["bar", "foo"]);
+        assert_eq!(get_info(&root), ["bar.list", "foo-extra.list", "foo.control", "foo.list"]);
+        assert!(fs::read_to_string(sysutils::in_root(&root, OPKG_STATUS)).unwrap().contains("Package: foo\nVersion: 1.0\n"));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn unknown_packages_are_refused() {
+        let root = make_root("opkg-unknown");
+        let mut om = OpkgMod::new(&false, &root);
+        assert_eq!(om.remove_package("baz".to_string()).unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(om.restore_package("baz".to_string()).unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(om.restore_package("bar".to_string()).unwrap_err().kind(), ErrorKind::AlreadyExists);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use colored::Colorize;
+
+/// This module removes a package from the pacman's local database, which is
+/// a directory per an installed package as /var/lib/pacman/local/<name>-<version>/
+/// with "desc", "files" and "mtree" in it. Unregistering a package is moving its
+/// directory away from the database to /var/lib/pacman/local.limopack.bkp/
+/// and registering it back is moving it from there back to the database.
+///
+/// The database is not locked here: the caller holds pacman's lock (see `PacmanLock`)
+/// from before it is loaded until it is saved.
+///
+use super::rmpak::PackMod;
+use crate::sysutils;
+
+pub static PACMAN_LOCAL_D: &str = "/var/lib/pacman/local";
+pub static PACMAN_DB_LOCK: &str = "/var/lib/pacman/db.lck";
+
+/// Held pacman database lock. pacman creates "db.lck" exclusively for the time it is
+/// modifying the database and refuses to run, while it exists. The lock file is removed,
+/// when this is dropped.
+pub struct PacmanLock {
+    path: PathBuf,
+}
+
+impl PacmanLock {
+    /// Create the lock file. If `wait` is false and the database is locked, then an error
+    /// is returned immediately, otherwise it is waited for the lock file to be removed.
+    pub fn acquire(debug: &bool, root: &Path, wait: bool) -> Result<Self, Error> {
+        let path = PathBuf::from(sysutils::in_root(root, PACMAN_DB_LOCK));
+        if *debug {
+            log::debug!("Locking \"{}\"", path.to_str().unwrap().bright_yellow());
+        }
+
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(PacmanLock { path }),
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                    if !wait {
+                        return Err(Error::new(
+                            ErrorKind::WouldBlock,
+                            format!("{} exists, pacman is running or it has left the lock behind", path.to_str().unwrap()),
+                        ));
+                    }
+                    thread::sleep(Duration::from_secs(1));
+                }
+                Err(err) => return Err(Error::new(err.kind(), format!("Unable to lock {}: {}", path.to_str().unwrap(), err))),
+            }
+        }
+    }
+}
+
+impl Drop for PacmanLock {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            log::error!("Unable to unlock {}: {}", self.path.to_str().unwrap().bright_yellow(), err);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct PacmanMod<'a> {
+    removed: Vec<PathBuf>,
+
+    // Backed up package directories, those are registered back
+    restored: Vec<PathBuf>,
+    local_path: String,
+    debug: &'a bool,
+}
+
+impl<'a> PacmanMod<'a> {
+    pub fn new(debug: &'a bool, root: &Path) -> Self {
+        PacmanMod { removed: vec![], restored: vec![], local_path: sysutils::in_root(root, PACMAN_LOCAL_D), debug }
+    }
+
+    /// Get a directory, where removed package directories are kept
+    fn get_backup_path(&self) -> PathBuf {
+        PathBuf::from(format!("{}.limopack.bkp", self.local_path))
+    }
+
+    /// Get a package name from its "desc" file, which is in the following format:
+    ///
+    ///     %NAME%
+    ///     linux
+    ///
+    ///     %VERSION%
+    ///     6.4.12.arch1-1
+    ///     ...
+    fn get_desc_name(&self, pkg_path: &Path) -> Option<String> {
+        let data = fs::read_to_string(pkg_path.join("desc")).ok()?;
+        let mut lines = data.lines().map(|l| l.trim());
+        while let Some(line) = lines.next() {
+            if line == "%NAME%" {
+                return lines.next().map(|l| l.to_string());
+            }
+        }
+
+        None
+    }
+
+    /// Resolve a package directory in a database (the local one or its backup) from the package name
+    fn find_package(&self, db_path: &Path, pn: &str) -> Option<PathBuf> {
+        for e in fs::read_dir(db_path).ok()?.flatten() {
+            let fname = e.file_name().to_str().unwrap_or_default().to_string();
+
+            // Directory is named as "<name>-<version>-<release>", so the name is not
+            // reliably extractable from it, as the name can also contain dashes.
+            if !e.path().is_dir() || !fname.starts_with(&format!("{}-", pn)) {
+                continue;
+            }
+
+            if self.get_desc_name(&e.path()).as_deref() == Some(pn) {
+                return Some(e.path());
+            }
+        }
+
+        None
+    }
+}
+
+impl PackMod for PacmanMod<'_> {
+    /// Remove package from the index. This still keeps only the state of the modpack,
+    /// but does not writes anything to the disk.
+    fn remove_package(&mut self, pn: String) -> Result<(), Error> {
+        log::info!("Looking for \"{}\" package...", pn.bright_yellow());
+        match self.find_package(Path::new(&self.local_path), &pn) {
+            Some(pkg_path) => {
+                log::info!("Altering package manager database for \"{}\"", pn.bright_yellow());
+                self.removed.push(pkg_path);
+            }
+            None => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("Package \"{}\" was not found in the database", pn.bright_yellow()),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Register back a package, which was previously removed from the index.
+    fn restore_package(&mut self, pn: String) -> Result<(), Error> {
+        log::info!("Looking for \"{}\" package in backups...", pn.bright_yellow());
+        if self.find_package(Path::new(&self.local_path), &pn).is_some() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("Package \"{}\" is already registered in the database", pn.bright_yellow()),
+            ));
+        }
+
+        match self.find_package(&self.get_backup_path(), &pn) {
+            Some(pkg_path) => {
+                log::info!("Restoring \"{}\" from {}", pn.bright_yellow(), pkg_path.to_str().unwrap().bright_yellow());
+                self.restored.push(pkg_path);
+            }
+            None => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!(
+                        "Package \"{}\" was not found in backups at {}",
+                        pn.bright_yellow(),
+                        self.get_backup_path().to_str().unwrap()
+                    ),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get names of all packages in the database.
+    fn list_packages(&self) -> Vec<String> {
+        let mut names: Vec<String> = vec![];
+        for e in fs::read_dir(&self.local_path).into_iter().flatten().flatten() {
+            if let Some(name) = self.get_desc_name(&e.path()) {
+                names.push(name);
+            }
+        }
+
+        names
+    }
+
+    /// Save the current state to the disk.
+    fn save(&self) -> Result<(), Error> {
+        log::info!("Save changes to the pacman database");
+        let bkp_path = self.get_backup_path();
+        fs::create_dir_all(&bkp_path)?;
+
+        for pkg_path in &self.removed {
+            let dst = bkp_path.join(pkg_path.file_name().unwrap());
+            if *self.debug {
+                log::debug!(
+                    "Moving \"{}\" to \"{}\"",
+                    pkg_path.to_str().unwrap().bright_yellow(),
+                    dst.to_str().unwrap().bright_yellow()
+                );
+            }
+
+            // Previous backup of the very same package version
+            if dst.exists() {
+                fs::remove_dir_all(&dst)?;
+            }
+            fs::rename(pkg_path, dst)?;
+        }
+
+        for pkg_path in &self.restored {
+            let dst = Path::new(&self.local_path).join(pkg_path.file_name().unwrap());
+            if *self.debug {
+                log::debug!(
+                    "Moving \"{}\" to \"{}\"",
+                    pkg_path.to_str().unwrap().bright_yellow(),
+                    dst.to_str().unwrap().bright_yellow()
+                );
+            }
+            fs::rename(pkg_path, dst)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PacmanLock, PacmanMod, PACMAN_DB_LOCK, PACMAN_LOCAL_D};
+    use crate::{pakmod::rmpak::PackMod, sysutils};
+    use std::{
+        env, fs,
+        io::ErrorKind,
+        path::{Path, PathBuf},
+        process,
+    };
+
+    /// Create an image root with pacman database of "linux" and "linux-firmware",
+    /// whose directory also starts with "linux-"
+    fn make_root(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("limopack-test-{}-{}", process::id(), name));
+        for (dname, pn) in [("linux-6.4.12.arch1-1", "linux"), ("linux-firmware-20230804.7be2766d-2", "linux-firmware")] {
+            let pkg_path = Path::new(&sysutils::in_root(&root, PACMAN_LOCAL_D)).join(dname);
+            fs::create_dir_all(&pkg_path).unwrap();
+            fs::write(pkg_path.join("desc"), format!("%NAME%\n{}\n\n%VERSION%\n1\n", pn)).unwrap();
+            fs::write(pkg_path.join("files"), "%FILES%\nusr/\n").unwrap();
+        }
+
+        root
+    }
+
+    fn get_packages(root: &Path) -> Vec<String> {
+        let mut out = PacmanMod::new(&false, root).list_packages();
+        out.sort();
+
+        out
+    }
+
+    #[test]
+    fn removed_package_is_restored() {
+        let root = make_root("pacman-restore");
+        let mut pm = PacmanMod::new(&false, &root);
+        pm.remove_package("linux".to_string()).unwrap();
+        pm.save().unwrap();
+
+        assert_eq!(get_packages(&root), ["linux-firmware"]);
+        let bkp_path = format!("{}.limopack.bkp/linux-6.4.12.arch1-1", sysutils::in_root(&root, PACMAN_LOCAL_D));
+        assert!(Path::new(&bkp_path).join("files").exists());
+
+        let mut pm = PacmanMod::new(&false, &root);
+        pm.restore_package("linux".to_string()).unwrap();
+        pm.save().unwrap();
+
+        assert_eq!(get_packages(&root), ["linux", "linux-firmware"]);
+        assert!(!Path::new(&bkp_path).exists());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn unknown_packages_are_refused() {
+        let root = make_root("pacman-unknown");
+        let mut pm = PacmanMod::new(&false, &root);
+        assert_eq!(pm.remove_package("linux-lts".to_string()).unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(pm.restore_package("linux-lts".to_string()).unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(pm.restore_package("linux".to_string()).unwrap_err().kind(), ErrorKind::AlreadyExists);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn lock_is_exclusive() {
+        let root = make_root("pacman-lock");
+        let lock_path = sysutils::in_root(&root, PACMAN_DB_LOCK);
+
+        let lock = PacmanLock::acquire(&false, &root, false).unwrap();
+        assert!(Path::new(&lock_path).exists());
+        assert_eq!(PacmanLock::acquire(&false, &root, false).err().unwrap().kind(), ErrorKind::WouldBlock);
+
+        drop(lock);
+        assert!(!Path::new(&lock_path).exists());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -3,4 +3,10 @@ use std::io::Error;
 pub trait PackMod {
     fn remove_package(&mut self, name: String) -> Result<(), Error>;
     fn save(&self) -> Result<(), Error>;
+
+    /// Get names of all packages in the database.
+    fn list_packages(&self) -> Vec<String>;
+
+    /// Register back a package, previously removed from the database.
+    fn restore_package(&mut self, name: String) -> Result<(), Error>;
 }
@@ -0,0 +1,257 @@
+//! Pending operations, those cannot be done right away, because the package manager
+//! is running and owns its database. Such operations are recorded and then performed
+//! later by processing a dpkg trigger, to which limopack package is interested in.
+//!
+//! Pending unregistrations are kept in /var/lib/limopack/pending.unregister as a list
+//! of package names (or patterns), one per a line. Pending commit is denoted by the
+//! presence of /var/lib/limopack/pending.commit file. Options of the deferred "--apply"
+//! (package manager, root directory, kernels, configuration and forcing) are kept in
+//! /var/lib/limopack/pending.options as "<key> = <value>" per a line, so pending
+//! operations are performed exactly as they were requested.
+//!
+//! Triggers are processed while dpkg is still running, so the pending operations are
+//! performed by a detached process, which waits for dpkg and its frontend to finish.
+//! Its output is written to /var/lib/limopack/pending.log
+
+use colored::Colorize;
+use std::{
+    env, fs,
+    fs::OpenOptions,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use crate::sysutils;
+
+pub static PENDING_D: &str = "/var/lib/limopack";
+pub static TRIGGER_NAME: &str = "limopack-apply";
+pub static DPKG_TRIGGER_EXE: &str = "/usr/bin/dpkg-trigger";
+static PENDING_UNREGISTER: &str = "pending.unregister";
+static PENDING_COMMIT: &str = "pending.commit";
+static PENDING_OPTIONS: &str = "pending.options";
+static PENDING_LOG: &str = "pending.log";
+
+/// Options of the deferred "--apply", those pending operations are performed with
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Options {
+    pub pm: Option<String>,
+    pub root: Option<String>,
+    pub kernel: Option<String>,
+    pub config: Option<String>,
+    pub force: bool,
+}
+
+impl Options {
+    /// Parse options data
+    fn parse(data: &str) -> Result<Self, Error> {
+        let mut opts = Options::default();
+        for line in data.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim().to_string()),
+                None => return Err(Error::new(ErrorKind::InvalidData, format!("Unsupported pending option \"{}\"", line))),
+            };
+
+            match key {
+                "pm" => opts.pm = Some(value),
+                "root" => opts.root = Some(value),
+                "kernel" => opts.kernel = Some(value),
+                "config" => opts.config = Some(value),
+                "force" => opts.force = value == "yes",
+                _ => return Err(Error::new(ErrorKind::InvalidData, format!("Unknown pending option \"{}\"", key))),
+            }
+        }
+
+        Ok(opts)
+    }
+
+    /// Format options data
+    fn format(&self) -> String {
+        let mut out = String::default();
+        for (key, value) in [("pm", &self.pm), ("root", &self.root), ("kernel", &self.kernel), ("config", &self.config)] {
+            if let Some(value) = value {
+                out.push_str(&format!("{} = {}\n", key, value));
+            }
+        }
+        out.push_str(&format!("force = {}\n", if self.force { "yes" } else { "no" }));
+
+        out
+    }
+}
+
+/// Get path of the pending unregistrations list
+fn get_unregister_path() -> PathBuf {
+    Path::new(PENDING_D).join(PENDING_UNREGISTER)
+}
+
+/// Get package names, those are pending for unregistration
+pub fn get_unregister() -> Vec<String> {
+    match fs::read_to_string(get_unregister_path()) {
+        Ok(data) => data.lines().map(|l| l.trim().to_string()).filter(|l| !l.is_empty()).collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Record package names for a later unregistration
+pub fn add_unregister(pkgnames: &[String]) -> Result<(), Error> {
+    let mut pending = get_unregister();
+    for pkgname in pkgnames {
+        if !pending.contains(pkgname) {
+            pending.push(pkgname.to_owned());
+        }
+    }
+
+    fs::create_dir_all(PENDING_D)?;
+    sysutils::write_atomic(get_unregister_path().to_str().unwrap(), &format!("{}\n", pending.join("\n")))
+}
+
+/// Remove package names from pending unregistrations, once they are processed
+pub fn remove_unregister(pkgnames: &[String]) -> Result<(), Error> {
+    let pending: Vec<String> = get_unregister().into_iter().filter(|p| !pkgnames.contains(p)).collect();
+    if pending.is_empty() {
+        if get_unregister_path().exists() {
+            fs::remove_file(get_unregister_path())?;
+        }
+        return Ok(());
+    }
+
+    sysutils::write_atomic(get_unregister_path().to_str().unwrap(), &format!("{}\n", pending.join("\n")))
+}
+
+/// Returns true if commit of the module changes to the disk is pending
+pub fn is_commit() -> bool {
+    Path::new(PENDING_D).join(PENDING_COMMIT).exists()
+}
+
+/// Record commit of the module changes for later
+pub fn set_commit() -> Result<(), Error> {
+    fs::create_dir_all(PENDING_D)?;
+    fs::write(Path::new(PENDING_D).join(PENDING_COMMIT), "")
+}
+
+/// Remove pending commit, once it is done
+pub fn clear_commit() -> Result<(), Error> {
+    let path = Path::new(PENDING_D).join(PENDING_COMMIT);
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
+
+/// Get options of the pending operations. These are default ones, if nothing is recorded.
+pub fn get_options() -> Result<Options, Error> {
+    match fs::read_to_string(Path::new(PENDING_D).join(PENDING_OPTIONS)) {
+        Ok(data) => Options::parse(&data),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Options::default()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Record options of the pending operations. Operations, pending already, are to be performed
+/// with the same options, so other ones are refused. Forcing is kept, once it is requested.
+pub fn set_options(opts: &Options) -> Result<(), Error> {
+    let path = Path::new(PENDING_D).join(PENDING_OPTIONS);
+    let mut opts = opts.to_owned();
+    if path.exists() {
+        let cur = get_options()?;
+        if (Options { force: cur.force, ..opts.to_owned() }) != cur {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Pending changes are recorded with other options ({}), apply them first with \"limopack --triggered\"",
+                    cur.format().trim().replace('\n', ", ")
+                ),
+            ));
+        }
+        opts.force = opts.force || cur.force;
+    }
+
+    fs::create_dir_all(PENDING_D)?;
+    sysutils::write_atomic(path.to_str().unwrap(), &opts.format())
+}
+
+/// Remove options of the pending operations, once all of them are done
+pub fn clear_options() -> Result<(), Error> {
+    let path = Path::new(PENDING_D).join(PENDING_OPTIONS);
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
+
+/// Run pending operations in a detached process, outside of the current dpkg transaction.
+/// The process reads options of the operations from the pending state.
+pub fn spawn_detached(debug: &bool) -> Result<(), Error> {
+    fs::create_dir_all(PENDING_D)?;
+    let log_path = Path::new(PENDING_D).join(PENDING_LOG);
+    let log_ptr = OpenOptions::new().create(true).append(true).open(&log_path)?;
+
+    let mut cmd = Command::new(env::current_exe()?);
+    cmd.arg("--triggered");
+    if *debug {
+        cmd.arg("--debug");
+    }
+
+    log::info!(
+        "Pending operations will be performed after dpkg has finished, see {}",
+        log_path.to_str().unwrap().bright_yellow()
+    );
+    sysutils::detach(&mut cmd)
+        .env_remove("DPKG_RUNNING_VERSION")
+        .stdin(Stdio::null())
+        .stdout(log_ptr.try_clone()?)
+        .stderr(log_ptr)
+        .spawn()?;
+
+    Ok(())
+}
+
+/// Activate limopack's dpkg trigger, so the pending operations are performed
+/// once dpkg has finished the current transaction.
+pub fn activate_trigger(debug: &bool) -> Result<(), Error> {
+    if *debug {
+        log::debug!("Activating \"{}\" trigger", TRIGGER_NAME.bright_yellow());
+    }
+
+    let out = Command::new(DPKG_TRIGGER_EXE).arg("--no-await").arg(TRIGGER_NAME).output()?;
+    if !out.status.success() {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!(
+                "Unable to activate \"{}\" trigger: {}",
+                TRIGGER_NAME,
+                String::from_utf8_lossy(&out.stderr).trim().bright_red()
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Options;
+
+    #[test]
+    fn options_round_trip() {
+        let opts = Options {
+            pm: Some("opkg".to_string()),
+            root: Some("/mnt/image".to_string()),
+            kernel: Some("6.1.0-13-amd64".to_string()),
+            config: None,
+            force: true,
+        };
+        assert_eq!(opts.format(), "pm = opkg\nroot = /mnt/image\nkernel = 6.1.0-13-amd64\nforce = yes\n");
+        assert_eq!(Options::parse(&opts.format()).unwrap(), opts);
+        assert_eq!(Options::parse(&Options::default().format()).unwrap(), Options::default());
+    }
+
+    #[test]
+    fn invalid_options_are_refused() {
+        assert!(Options::parse("pm = dpkg\nforce\n").is_err());
+        assert!(Options::parse("pm = dpkg\npurge = yes\n").is_err());
+        assert_eq!(Options::parse("\nforce = no\n\n").unwrap(), Options::default());
+    }
+}
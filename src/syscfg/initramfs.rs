@@ -0,0 +1,236 @@
+//! Modules, those are required by the configuration of initramfs generators.
+//! Removing them breaks the next initramfs generation or makes the system unbootable,
+//! so they are implicitly static.
+//!
+//! For initramfs-tools these are modules, listed in /etc/initramfs-tools/modules,
+//! and those implied by MODULES= setting of /etc/initramfs-tools/initramfs.conf
+//! and /etc/initramfs-tools/conf.d/*. For dracut these are modules, specified
+//! by add_drivers, force_drivers and drivers settings of /etc/dracut.conf and
+//! /etc/dracut.conf.d/*.conf files.
+
+use std::{fs, path::Path};
+
+use super::{get_dropins, get_shell_value, read_modules_list, ConfModule};
+use crate::mdb::modules::modinfo;
+
+pub static INITRAMFS_TOOLS_D: &str = "/etc/initramfs-tools";
+pub static DRACUT_CONF: &str = "/etc/dracut.conf";
+pub static DRACUT_CONF_D: &str = "/etc/dracut.conf.d";
+static DRACUT_DRIVERS: [&str; 3] = ["add_drivers", "force_drivers", "drivers"];
+
+// Modules, included by initramfs-tools with MODULES=most, after auto_add_modules() of its hook-functions:
+// file systems for root and /usr, keyboards, disk controllers, and what device-mapper and RAID need
+static MOST_MODULES: [&str; 10] = [
+    "re:^kernel/fs/(btrfs|ext[234]|f2fs|isofs|jfs|reiserfs|udf|xfs)/",
+    "re:^(atkbd|i8042|psmouse|usbhid|hid-generic)$",
+    "kernel/drivers/ata/**",
+    "re:^(scsi_mod|sd_mod|sr_mod|virtio_scsi|hv_storvsc|vmw_pvscsi|megaraid_sas|mpt3sas|aacraid|smartpqi|mpt(sas|spi|fc)|qla2xxx|lpfc)$",
+    "kernel/drivers/mmc/**",
+    "kernel/drivers/nvme/host/**",
+    "re:^(virtio_pci|virtio_mmio|virtio_blk|xen-blkfront|loop)$",
+    "kernel/drivers/usb/host/**",
+    "kernel/drivers/usb/storage/**",
+    "re:^(dm-mod|dm-crypt|md-mod|raid[0-9]+)$",
+];
+
+// Modules, included by initramfs-tools with MODULES=netboot (network and NFS)
+static NETBOOT_MODULES: [&str; 4] =
+    ["kernel/drivers/net/ethernet/**", "kernel/drivers/net/virtio_net", "kernel/fs/nfs/**", "kernel/net/sunrpc/**"];
+
+/// Get modules (or patterns), implied by MODULES= setting of initramfs-tools, or None if it is unknown.
+/// Loaded modules are only asked for with MODULES=dep.
+fn get_mode_modules(mode: &str, loaded: impl FnOnce() -> Vec<String>) -> Option<Vec<String>> {
+    match mode {
+        "most" => Some(MOST_MODULES.iter().map(|m| m.to_string()).collect()),
+        "netboot" => Some(NETBOOT_MODULES.iter().map(|m| m.to_string()).collect()),
+        "dep" => Some(loaded()),
+        "list" => Some(vec![]),
+        _ => None,
+    }
+}
+
+/// Get modules, required by initramfs-tools, configured in the specified directory
+fn get_initramfs_tools(root: &Path, debug: &bool, out: &mut Vec<ConfModule>) {
+    read_modules_list(&root.join("modules"), debug, out);
+
+    // The last MODULES= setting wins, configuration snippets are sourced after the main file
+    let mut mode: Option<(String, String)> = None;
+    let mut confs = vec![root.join("initramfs.conf")];
+    confs.extend(get_dropins(root.join("conf.d").to_str().unwrap(), None));
+    for conf in confs {
+        for line in fs::read_to_string(&conf).unwrap_or_default().lines() {
+            if let Some(value) = get_shell_value(line, "MODULES") {
+                mode = Some((value, conf.to_str().unwrap().to_string()));
+            }
+        }
+    }
+
+    let (mode, conf) = match mode {
+        Some(mode) => mode,
+        None => return,
+    };
+    let source = format!("{} (MODULES={})", conf, mode);
+
+    // Modules of the running system are included with MODULES=dep
+    let loaded = || {
+        if Path::new("/proc/modules").exists() {
+            modinfo::lsmod().into_iter().map(|m| m.name).collect()
+        } else {
+            log::warn!("Unable to get loaded modules, required by {}", source);
+            vec![]
+        }
+    };
+
+    match get_mode_modules(&mode, loaded) {
+        Some(modules) => out.extend(modules.into_iter().map(|name| ConfModule { name, source: source.to_owned() })),
+        None => log::warn!("Unknown initramfs-tools MODULES={} setting in {}", mode, conf),
+    }
+}
+
+/// Get modules, required by dracut, configured in the specified main file and drop-in directory
+fn get_dracut(conf: &Path, conf_d: &Path, debug: &bool, out: &mut Vec<ConfModule>) {
+    let mut confs = vec![conf.to_path_buf()];
+    confs.extend(get_dropins(conf_d.to_str().unwrap(), Some("conf")));
+
+    for conf in confs {
+        let data = match fs::read_to_string(&conf) {
+            Ok(data) => data,
+            Err(err) => {
+                if *debug {
+                    log::debug!("Skipping {}: {}", conf.to_str().unwrap(), err);
+                }
+                continue;
+            }
+        };
+
+        for line in data.lines() {
+            for drv in DRACUT_DRIVERS {
+                if let Some(value) = get_shell_value(line, drv) {
+                    out.extend(value.split_whitespace().map(|name| ConfModule {
+                        name: name.to_string(),
+                        source: format!("{} ({})", conf.to_str().unwrap(), drv),
+                    }));
+                }
+            }
+        }
+    }
+}
+
+/// Get modules, required by configuration of initramfs-tools and dracut
+pub fn get_modules(debug: &bool) -> Vec<ConfModule> {
+    let mut out: Vec<ConfModule> = vec![];
+    get_initramfs_tools(Path::new(INITRAMFS_TOOLS_D), debug, &mut out);
+    get_dracut(Path::new(DRACUT_CONF), Path::new(DRACUT_CONF_D), debug, &mut out);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_dracut, get_initramfs_tools, get_mode_modules, ConfModule};
+    use crate::mtree::kerman::kman::{get_module_name, is_module_pattern, ModPattern};
+    use std::{env, fs, process};
+
+    // A part of a typical kernel tree
+    static DISK_MODULES: [&str; 32] = [
+        "kernel/fs/ext4/ext4.ko",
+        "kernel/fs/xfs/xfs.ko",
+        "kernel/fs/btrfs/btrfs.ko",
+        "kernel/fs/ocfs2/ocfs2.ko",
+        "kernel/fs/ntfs3/ntfs3.ko",
+        "kernel/fs/cifs/cifs.ko",
+        "kernel/fs/fuse/fuse.ko",
+        "kernel/drivers/ata/ahci.ko",
+        "kernel/drivers/ata/libahci.ko",
+        "kernel/drivers/scsi/sd_mod.ko",
+        "kernel/drivers/scsi/virtio_scsi.ko",
+        "kernel/drivers/scsi/st.ko",
+        "kernel/drivers/scsi/iscsi_tcp.ko",
+        "kernel/drivers/scsi/fcoe/fcoe.ko",
+        "kernel/drivers/scsi/bnx2fc/bnx2fc.ko",
+        "kernel/drivers/scsi/megaraid/megaraid_sas.ko",
+        "kernel/drivers/nvme/host/nvme.ko",
+        "kernel/drivers/nvme/host/nvme-core.ko",
+        "kernel/drivers/nvme/target/nvmet.ko",
+        "kernel/drivers/mmc/core/mmc_block.ko",
+        "kernel/drivers/block/virtio_blk.ko",
+        "kernel/drivers/block/loop.ko",
+        "kernel/drivers/block/nbd.ko",
+        "kernel/drivers/block/zram/zram.ko",
+        "kernel/drivers/block/drbd/drbd.ko",
+        "kernel/drivers/virtio/virtio_pci.ko",
+        "kernel/drivers/virtio/virtio_balloon.ko",
+        "kernel/drivers/md/dm-mod.ko",
+        "kernel/drivers/md/bcache/bcache.ko",
+        "kernel/drivers/usb/storage/usb-storage.ko",
+        "kernel/drivers/net/ethernet/intel/e1000e/e1000e.ko",
+        "kernel/sound/core/snd.ko",
+    ];
+
+    /// Get modules of the disk, those are matched by modules or patterns
+    fn resolve(modules: &[String]) -> Vec<&'static str> {
+        let matches = |m: &String, dm: &str| match is_module_pattern(m) {
+            true => ModPattern::new(m).unwrap().matches(dm),
+            false => get_module_name(m) == get_module_name(dm),
+        };
+        DISK_MODULES.iter().copied().filter(|dm| modules.iter().any(|m| matches(m, dm))).collect()
+    }
+
+    #[test]
+    fn most_keeps_storage_and_root_file_systems() {
+        let most = resolve(&get_mode_modules("most", Vec::new).unwrap());
+        for name in ["ext4", "xfs", "btrfs", "ahci", "sd_mod", "virtio_scsi", "megaraid_sas", "nvme", "mmc_block", "virtio_blk"] {
+            assert!(most.iter().any(|dm| get_module_name(dm) == name), "{}", name);
+        }
+        for name in ["ocfs2", "ntfs3", "cifs", "fcoe", "bnx2fc", "iscsi_tcp", "nvmet", "zram", "drbd", "virtio_balloon", "snd"] {
+            assert!(!most.iter().any(|dm| get_module_name(dm) == name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn dep_and_list_keep_much_less() {
+        let most = resolve(&get_mode_modules("most", Vec::new).unwrap());
+        let loaded = || vec!["ext4".to_string(), "nvme".to_string(), "nvme_core".to_string(), "e1000e".to_string()];
+        let dep = resolve(&get_mode_modules("dep", loaded).unwrap());
+        let list = resolve(&get_mode_modules("list", || panic!("loaded modules are not needed")).unwrap());
+
+        assert_eq!(dep.len(), 4);
+        assert!(list.is_empty());
+        assert!(most.len() >= dep.len() * 4, "{:?}", most);
+        assert!(get_mode_modules("all", Vec::new).is_none());
+    }
+
+    #[test]
+    fn last_modules_setting_wins() {
+        let root = env::temp_dir().join(format!("limopack-test-{}-initramfs-tools", process::id()));
+        fs::create_dir_all(root.join("conf.d")).unwrap();
+        fs::write(root.join("modules"), "# Comment\nxfs\n").unwrap();
+        fs::write(root.join("initramfs.conf"), "MODULES=most\nBUSYBOX=auto\n").unwrap();
+        fs::write(root.join("conf.d").join("modules"), "MODULES=list\n").unwrap();
+
+        let mut out: Vec<ConfModule> = vec![];
+        get_initramfs_tools(&root, &false, &mut out);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(out.iter().map(|m| m.name.as_str()).collect::<Vec<&str>>(), ["xfs"]);
+        assert_eq!(resolve(&out.into_iter().map(|m| m.name).collect::<Vec<String>>()), ["kernel/fs/xfs/xfs.ko"]);
+    }
+
+    #[test]
+    fn dracut_drivers_are_collected() {
+        let root = env::temp_dir().join(format!("limopack-test-{}-dracut", process::id()));
+        fs::create_dir_all(root.join("dracut.conf.d")).unwrap();
+        fs::write(root.join("dracut.conf"), "hostonly=\"yes\"\nadd_drivers+=\" nvme ahci \"\n").unwrap();
+        fs::write(root.join("dracut.conf.d").join("10-raid.conf"), "force_drivers+=\"raid1\" # mirror\n#drivers=\"xfs\"\n")
+            .unwrap();
+        fs::write(root.join("dracut.conf.d").join("20-ignored.txt"), "add_drivers+=\" floppy \"\n").unwrap();
+
+        let mut out: Vec<ConfModule> = vec![];
+        get_dracut(&root.join("dracut.conf"), &root.join("dracut.conf.d"), &false, &mut out);
+        fs::remove_dir_all(&root).unwrap();
+
+        let raid_conf = format!("{}/dracut.conf.d/10-raid.conf (force_drivers)", root.to_str().unwrap());
+        assert_eq!(out.iter().map(|m| m.name.as_str()).collect::<Vec<&str>>(), ["nvme", "ahci", "raid1"]);
+        assert_eq!(out[2].source, raid_conf);
+    }
+}
@@ -0,0 +1,132 @@
+//! System configuration of other tools, which affects what kernel modules
+//! are needed on the system.
+
+pub mod initramfs;
+pub mod modload;
+pub mod modprobe;
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Module (or a pattern of modules), required by a configuration
+pub struct ConfModule {
+    pub name: String,
+    // Where it is configured, e.g. a file path
+    pub source: String,
+}
+
+/// Get files of a drop-in configuration directory, sorted by their names,
+/// optionally only those with a specific extension (e.g. "conf").
+pub fn get_dropins(dir: &str, ext: Option<&str>) -> Vec<PathBuf> {
+    let mut out: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && (ext.is_none() || p.extension().and_then(|e| e.to_str()) == ext))
+        .collect();
+    out.sort();
+
+    out
+}
+
+/// Get files of several drop-in configuration directories with the specified extension,
+/// sorted by their names. A file of the former directory overrides the file of the same
+/// name in the latter ones, as systemd and kmod do (e.g. /etc over /usr/lib).
+pub fn get_dropins_merged(dirs: &[&str], ext: &str) -> Vec<PathBuf> {
+    let mut confs: HashMap<String, PathBuf> = HashMap::default();
+    for dir in dirs {
+        for conf in get_dropins(dir, Some(ext)) {
+            confs.entry(conf.file_name().unwrap().to_str().unwrap().to_string()).or_insert(conf);
+        }
+    }
+
+    let mut out: Vec<(String, PathBuf)> = confs.into_iter().collect();
+    out.sort();
+
+    out.into_iter().map(|(_, conf)| conf).collect()
+}
+
+/// Get a value of a shell variable assignment, such as `MODULES="most"` or `add_drivers+=" nvme "`,
+/// if the line assigns (or appends) to the specified variable.
+pub fn get_shell_value(line: &str, name: &str) -> Option<String> {
+    let (key, value) = line.trim().split_once('=')?;
+    if key.trim().trim_end_matches('+').trim() != name {
+        return None;
+    }
+
+    let value = value.trim();
+    let value = match value.chars().next() {
+        Some(q) if q == '"' || q == '\'' => value.trim_start_matches(q).split(q).next().unwrap_or_default(),
+        _ => value.split('#').next().unwrap_or_default(),
+    };
+
+    Some(value.trim().to_string())
+}
+
+/// Read modules, those are listed one per a line, optionally with their arguments.
+/// Lines, starting with "#" or ";", are comments.
+pub fn read_modules_list(path: &Path, debug: &bool, out: &mut Vec<ConfModule>) {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(err) => {
+            if *debug {
+                log::debug!("Skipping {}: {}", path.to_str().unwrap(), err);
+            }
+            return;
+        }
+    };
+
+    for line in data.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if let Some(name) = line.split_whitespace().next() {
+            out.push(ConfModule { name: name.to_string(), source: path.to_str().unwrap().to_string() });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_shell_value, read_modules_list, ConfModule};
+    use std::{env, fs, process};
+
+    #[test]
+    fn shell_values_are_unquoted() {
+        assert_eq!(get_shell_value("MODULES=most", "MODULES").as_deref(), Some("most"));
+        assert_eq!(get_shell_value("  MODULES = dep # comment", "MODULES").as_deref(), Some("dep"));
+        assert_eq!(get_shell_value("add_drivers+=\" nvme ahci \"", "add_drivers").as_deref(), Some("nvme ahci"));
+        assert_eq!(get_shell_value("drivers='xfs' # comment", "drivers").as_deref(), Some("xfs"));
+        assert_eq!(
+            get_shell_value("force_drivers+=\"raid1 # not a comment\"", "force_drivers").as_deref(),
+            Some("raid1 # not a comment")
+        );
+        assert_eq!(get_shell_value("MODULES=", "MODULES").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn other_lines_are_not_values() {
+        assert!(get_shell_value("MODULES=most", "BUSYBOX").is_none());
+        assert!(get_shell_value("add_drivers_extra=nvme", "add_drivers").is_none());
+        assert!(get_shell_value("# MODULES", "MODULES").is_none());
+        assert!(get_shell_value("", "MODULES").is_none());
+    }
+
+    #[test]
+    fn modules_lists_skip_comments_and_arguments() {
+        let path = env::temp_dir().join(format!("limopack-test-{}-modules", process::id()));
+        fs::write(&path, "# Comment\n; Comment\n\n  loop max_loop=64\nnvme\n").unwrap();
+
+        let mut out: Vec<ConfModule> = vec![];
+        read_modules_list(&path, &false, &mut out);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(out.iter().map(|m| m.name.as_str()).collect::<Vec<&str>>(), ["loop", "nvme"]);
+        assert_eq!(out[0].source, path.to_str().unwrap());
+    }
+}
@@ -0,0 +1,87 @@
+//! Modules, those are configured to be loaded at boot: listed in /etc/modules,
+//! in *.conf files of modules-load.d directories, or specified in the kernel
+//! command line with "modules-load=", "rd.modules-load=" and "rd.driver.pre=".
+//!
+//! Files of modules-load.d directories with the same name override each other,
+//! in the order of the directories below, as systemd-modules-load does.
+
+use std::{fs, path::PathBuf};
+
+use super::{get_dropins_merged, read_modules_list, ConfModule};
+
+pub static ETC_MODULES: &str = "/etc/modules";
+pub static MODULES_LOAD_D: [&str; 5] = [
+    "/etc/modules-load.d",
+    "/run/modules-load.d",
+    "/usr/local/lib/modules-load.d",
+    "/usr/lib/modules-load.d",
+    "/lib/modules-load.d",
+];
+pub static CMDLINE: &str = "/proc/cmdline";
+static CMDLINE_PARAMS: [&str; 3] = ["modules-load", "rd.modules-load", "rd.driver.pre"];
+
+/// Get modules, specified in the kernel command line, read from the specified file
+fn get_cmdline(path: &str, debug: &bool, out: &mut Vec<ConfModule>) {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(err) => {
+            if *debug {
+                log::debug!("Skipping {}: {}", path, err);
+            }
+            return;
+        }
+    };
+
+    // Arguments after "--" are passed to init
+    for arg in data.split_whitespace().take_while(|a| *a != "--") {
+        if let Some((param, value)) = arg.split_once('=') {
+            if CMDLINE_PARAMS.contains(&param) {
+                out.extend(
+                    value
+                        .split(',')
+                        .filter(|m| !m.is_empty())
+                        .map(|m| ConfModule { name: m.to_string(), source: format!("{} ({})", path, param) }),
+                );
+            }
+        }
+    }
+}
+
+/// Get modules, configured to be loaded at boot
+pub fn get_modules(debug: &bool) -> Vec<ConfModule> {
+    let mut out: Vec<ConfModule> = vec![];
+    read_modules_list(&PathBuf::from(ETC_MODULES), debug, &mut out);
+
+    for conf in get_dropins_merged(&MODULES_LOAD_D, "conf") {
+        read_modules_list(&conf, debug, &mut out);
+    }
+
+    get_cmdline(CMDLINE, debug, &mut out);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_cmdline, ConfModule};
+    use std::{env, fs, process};
+
+    #[test]
+    fn cmdline_modules_are_collected() {
+        let path = env::temp_dir().join(format!("limopack-test-{}-cmdline", process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(
+            path,
+            "BOOT_IMAGE=/vmlinuz root=UUID=1234 modules-load=nvme,,dm-crypt rd.driver.pre=ahci quiet \
+                         rd.modules-load=raid1 modules-load -- modules-load=ignored\n",
+        )
+        .unwrap();
+
+        let mut out: Vec<ConfModule> = vec![];
+        get_cmdline(path, &false, &mut out);
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(out.iter().map(|m| m.name.as_str()).collect::<Vec<&str>>(), ["nvme", "dm-crypt", "ahci", "raid1"]);
+        assert_eq!(out[2].source, format!("{} (rd.driver.pre)", path));
+    }
+}
@@ -0,0 +1,99 @@
+//! Modules, those are blacklisted in modprobe.d configuration, i.e. by "blacklist <module>"
+//! or by "install <module> /bin/false" (or "/bin/true") commands, which disable loading
+//! of a module completely.
+//!
+//! Files of modprobe.d directories with the same name override each other,
+//! in the order of the directories below, as kmod does.
+
+use std::fs;
+
+use super::{get_dropins_merged, ConfModule};
+
+pub static MODPROBE_D: [&str; 5] =
+    ["/etc/modprobe.d", "/run/modprobe.d", "/usr/local/lib/modprobe.d", "/usr/lib/modprobe.d", "/lib/modprobe.d"];
+
+// Commands, those effectively disable loading a module, if used for "install"
+static NOOP_CMDS: [&str; 2] = ["false", "true"];
+
+/// Returns true if an install command does nothing, e.g. "/bin/false" or "true"
+fn is_noop_cmd(cmd: &[&str]) -> bool {
+    cmd.len() == 1 && NOOP_CMDS.contains(&cmd[0].rsplit('/').next().unwrap_or_default())
+}
+
+/// Get modules, blacklisted in modprobe.d configuration
+pub fn get_blacklisted(debug: &bool) -> Vec<ConfModule> {
+    get_blacklisted_in(&MODPROBE_D, debug)
+}
+
+/// Get modules, blacklisted in the specified modprobe.d directories
+fn get_blacklisted_in(dirs: &[&str], debug: &bool) -> Vec<ConfModule> {
+    let mut out: Vec<ConfModule> = vec![];
+    for conf in get_dropins_merged(dirs, "conf") {
+        let data = match fs::read_to_string(&conf) {
+            Ok(data) => data,
+            Err(err) => {
+                if *debug {
+                    log::debug!("Skipping {}: {}", conf.to_str().unwrap(), err);
+                }
+                continue;
+            }
+        };
+
+        // Lines, ending with a backslash, are continued on the next line
+        for line in data.replace("\\\n", " ").lines().map(|l| l.trim()) {
+            if line.starts_with('#') {
+                continue;
+            }
+
+            let args: Vec<&str> = line.split_whitespace().collect();
+            match args.as_slice() {
+                ["blacklist", name] => {
+                    out.push(ConfModule { name: name.to_string(), source: format!("{} (blacklist)", conf.to_str().unwrap()) })
+                }
+                ["install", name, cmd @ ..] if is_noop_cmd(cmd) => {
+                    out.push(ConfModule { name: name.to_string(), source: format!("{} (install)", conf.to_str().unwrap()) })
+                }
+                _ => {}
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_blacklisted_in, is_noop_cmd};
+    use std::{env, fs, process};
+
+    #[test]
+    fn noop_commands_are_detected() {
+        for cmd in [&["/bin/false"][..], &["true"], &["/usr/bin/true"]] {
+            assert!(is_noop_cmd(cmd), "{:?}", cmd);
+        }
+        for cmd in [&["/bin/false", "foo"][..], &["/sbin/modprobe", "--ignore-install", "foo"], &[]] {
+            assert!(!is_noop_cmd(cmd), "{:?}", cmd);
+        }
+    }
+
+    #[test]
+    fn blacklisted_modules_are_collected() {
+        let root = env::temp_dir().join(format!("limopack-test-{}-modprobe", process::id()));
+        let (etc, lib) = (root.join("etc"), root.join("lib"));
+        fs::create_dir_all(&etc).unwrap();
+        fs::create_dir_all(&lib).unwrap();
+        fs::write(etc.join("blacklist.conf"), "# blacklist pcspkr\nblacklist floppy\ninstall usb-storage \\\n  /bin/true\n")
+            .unwrap();
+        fs::write(etc.join("options.conf"), "options snd-hda-intel power_save=1\ninstall foo /sbin/modprobe bar\n").unwrap();
+        fs::write(etc.join("other.txt"), "blacklist nouveau\n").unwrap();
+        fs::write(lib.join("blacklist.conf"), "blacklist pcspkr\n").unwrap();
+        fs::write(lib.join("fbdev.conf"), "blacklist vesafb extra\nblacklist   radeonfb\n").unwrap();
+
+        let out = get_blacklisted_in(&[etc.to_str().unwrap(), lib.to_str().unwrap()], &false);
+        fs::remove_dir_all(&root).unwrap();
+
+        // Same named file of the former directory overrides the latter one
+        assert_eq!(out.iter().map(|m| m.name.as_str()).collect::<Vec<&str>>(), ["floppy", "usb-storage", "radeonfb"]);
+        assert_eq!(out[1].source, format!("{} (install)", etc.join("blacklist.conf").to_str().unwrap()));
+    }
+}
@@ -1,19 +1,34 @@
-use std::io::{Error, ErrorKind};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{Error, ErrorKind, Write},
+    mem,
+    os::unix::{fs::MetadataExt, io::AsRawFd, process::CommandExt},
+    path::Path,
+    process::Command,
+};
 
-#[link(name = "c")]
-extern "C" {
-    fn geteuid() -> u32;
-    fn getegid() -> u32;
-}
+static MOUNTINFO: &str = "/proc/self/mountinfo";
+static SYS_DEV_BLOCK: &str = "/sys/dev/block";
+
+// Files, those exist only in containers of a specific kind
+static CONTAINER_FILES: [(&str, &str); 2] = [("/.dockerenv", "docker"), ("/run/.containerenv", "podman")];
+
+// Kinds of containers, which can be found in cgroups of the init process
+static CGROUP_CONTAINERS: [&str; 5] = ["docker", "lxc", "kubepods", "containerd", "libpod"];
+
+/// Mount points, those are needed to boot
+pub static BOOT_MOUNTS: [&str; 3] = ["/", "/boot", "/boot/efi"];
 
 /// Returns true if the specified UID matches
 fn is_uid(uid: i8) -> bool {
-    unsafe { geteuid() == uid.try_into().unwrap() }
+    unsafe { libc::geteuid() == uid.try_into().unwrap() }
 }
 
 // Returns true if the specified GID matches
 fn is_gid(gid: i8) -> bool {
-    unsafe { getegid() == gid.try_into().unwrap() }
+    unsafe { libc::getegid() == gid.try_into().unwrap() }
 }
 
 /// Returns no error if user is root
@@ -23,3 +38,335 @@ pub fn user_is_root() -> Result<(), Error> {
     }
     Ok(())
 }
+
+/// Take an exclusive lock on the whole file, the same way dpkg and apt are doing it.
+/// If `wait` is false and the file is already locked, then "WouldBlock" error is returned.
+/// The lock is held until the returned file is closed.
+pub fn lock_file(path: &str, wait: bool) -> Result<File, Error> {
+    let fptr = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+
+    // fcntl(2) record lock, zero start and length lock the whole file
+    let mut flock: libc::flock = unsafe { mem::zeroed() };
+    flock.l_type = libc::F_WRLCK as libc::c_short;
+    flock.l_whence = libc::SEEK_SET as libc::c_short;
+
+    if unsafe { libc::fcntl(fptr.as_raw_fd(), if wait { libc::F_SETLKW } else { libc::F_SETLK }, &flock) } == -1 {
+        let err = Error::last_os_error();
+        return Err(match err.raw_os_error() {
+            Some(libc::EACCES) | Some(libc::EAGAIN) => {
+                Error::new(ErrorKind::WouldBlock, format!("{} is locked by another process", path))
+            }
+            _ => err,
+        });
+    }
+
+    Ok(fptr)
+}
+
+/// Make a command to run in its own session, so it is not terminated
+/// together with the calling process or its terminal.
+pub fn detach(cmd: &mut Command) -> &mut Command {
+    unsafe {
+        cmd.pre_exec(|| {
+            libc::setsid();
+            Ok(())
+        })
+    }
+}
+
+/// Write a file atomically: the data is written to a temporary file next to it,
+/// which then replaces the original one. Permissions of the original file are kept.
+pub fn write_atomic(path: &str, data: &str) -> Result<(), Error> {
+    let tmp_path = format!("{}.limopack-new", path);
+    let res = (|| {
+        let mut fptr = File::create(&tmp_path)?;
+        if let Ok(meta) = fs::metadata(path) {
+            fptr.set_permissions(meta.permissions())?;
+        }
+        fptr.write_all(data.as_bytes())?;
+        fptr.sync_all()?;
+        fs::rename(&tmp_path, path)
+    })();
+
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    res
+}
+
+/// Get a path within a root directory of a system, e.g. of an image being provisioned
+pub fn in_root(root: &Path, path: &str) -> String {
+    root.join(path.trim_start_matches('/')).to_str().unwrap().to_string()
+}
+
+/// Get version of the running kernel
+pub fn get_running_kernel() -> Result<String, Error> {
+    match fs::read_to_string("/proc/sys/kernel/osrelease") {
+        Ok(data) => Ok(data.trim().to_string()),
+        Err(err) => Err(Error::new(err.kind(), format!("Unable to get version of the running kernel: {}", err))),
+    }
+}
+
+/// Detect a container, the system is running in, returning its kind (e.g. "docker", "podman" or "lxc").
+/// Inside a container, the kernel, /proc/modules and sysfs are those of the host.
+pub fn get_container() -> Option<String> {
+    for (path, kind) in CONTAINER_FILES {
+        if Path::new(path).exists() {
+            return Some(kind.to_string());
+        }
+    }
+
+    // Set by systemd-nspawn, LXC and others, but the environment of init is readable only by root
+    if let Ok(kind) = fs::read_to_string("/run/systemd/container") {
+        if !kind.trim().is_empty() {
+            return Some(kind.trim().to_string());
+        }
+    }
+    if let Ok(env) = fs::read("/proc/1/environ") {
+        for var in env.split(|b| *b == 0) {
+            if let Some(kind) = var.strip_prefix(b"container=") {
+                return Some(String::from_utf8_lossy(kind).to_string());
+            }
+        }
+    }
+
+    for line in fs::read_to_string("/proc/1/cgroup").unwrap_or_default().lines() {
+        if let Some(kind) = CGROUP_CONTAINERS.iter().find(|k| line.contains(*k)) {
+            return Some(kind.to_string());
+        }
+    }
+
+    None
+}
+
+/// Returns true if a string is a shell-like wildcard pattern
+pub fn is_wildcard(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+/// Convert a shell-like wildcard pattern to a regular expression, which is not anchored.
+/// "*" and "?" match any characters within a path component only, "**" matches across
+/// components ("**/" also matches none of them) and "[...]" is a class of characters,
+/// which is negated by "[!...]".
+pub fn glob_to_regex(glob: &str) -> String {
+    let mut expr = String::default();
+    let mut in_class = false;
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '[' if !in_class => {
+                in_class = true;
+                expr.push(c);
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    expr.push('^');
+                }
+                // Closing bracket right at the start is a member of the class
+                if chars.peek() == Some(&']') {
+                    chars.next();
+                    expr.push_str("\\]");
+                }
+            }
+            ']' if in_class => {
+                in_class = false;
+                expr.push(c);
+            }
+            '[' | '\\' | '&' | '~' | '^' if in_class => {
+                expr.push('\\');
+                expr.push(c);
+            }
+            c if in_class => expr.push(c),
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    expr.push_str("(.*/)?");
+                } else {
+                    expr.push_str(".*");
+                }
+            }
+            '*' => expr.push_str("[^/]*"),
+            '?' => expr.push_str("[^/]"),
+            c => expr.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    expr
+}
+
+/// Compare version strings, such as kernel versions "6.1.0-13-amd64" and "6.1.0-9-amd64",
+/// so the numeric parts are compared as numbers and the rest as text.
+pub fn version_cmp(a: &str, b: &str) -> Ordering {
+    // Split to alternating runs of digits and non-digits
+    fn split(v: &str) -> Vec<String> {
+        let mut out: Vec<String> = vec![];
+        for c in v.chars() {
+            match out.last_mut() {
+                Some(l) if l.chars().last().unwrap().is_ascii_digit() == c.is_ascii_digit() => l.push(c),
+                _ => out.push(c.to_string()),
+            }
+        }
+        out
+    }
+
+    for (pa, pb) in split(a).iter().zip(split(b).iter()) {
+        let ord = match (pa.parse::<u64>(), pb.parse::<u64>()) {
+            (Ok(na), Ok(nb)) => na.cmp(&nb),
+            _ => pa.cmp(pb),
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+
+    split(a).len().cmp(&split(b).len())
+}
+
+/// Get "<major>:<minor>" number of a block device node, e.g. /dev/sda1
+fn get_device_number(path: &str) -> Option<String> {
+    let rdev = fs::metadata(path).ok()?.rdev();
+
+    // Same as gnu_dev_major() and gnu_dev_minor() of glibc
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & 0xffff_f000);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & 0xffff_ff00);
+
+    Some(format!("{}:{}", major, minor))
+}
+
+/// Get modules of drivers of a block device ("<major>:<minor>"), of its parents in sysfs
+/// (e.g. a disk of a partition, its controller and bus) and of its underlying devices
+/// (e.g. of a device mapper or a software RAID). Built-in drivers have no modules.
+fn get_block_device_modules(dev: &str, out: &mut Vec<String>) {
+    let path = match fs::canonicalize(Path::new(SYS_DEV_BLOCK).join(dev)) {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+
+    for slave in fs::read_dir(path.join("slaves")).into_iter().flatten().flatten() {
+        if let Ok(sdev) = fs::read_to_string(slave.path().join("dev")) {
+            get_block_device_modules(sdev.trim(), out);
+        }
+    }
+
+    let mut dir = Some(path.as_path());
+    while let Some(d) = dir {
+        if let Ok(module) = fs::read_link(d.join("driver").join("module")) {
+            if let Some(name) = module.file_name().and_then(|n| n.to_str()) {
+                if !out.iter().any(|m| m == name) {
+                    out.push(name.to_string());
+                }
+            }
+        }
+        dir = d.parent();
+    }
+}
+
+/// Get modules, those are needed to mount root and boot filesystems: drivers of their block
+/// devices and the filesystems themselves, each with a mount point it is needed for.
+pub fn get_boot_modules() -> Vec<(String, String)> {
+    // Mount point to its device number, filesystem type and source. The last mount wins.
+    let mut mounts: HashMap<String, (String, String, String)> = HashMap::default();
+    for line in fs::read_to_string(MOUNTINFO).unwrap_or_default().lines() {
+        let (pre, post) = match line.split_once(" - ") {
+            Some((pre, post)) => (pre.split_whitespace().collect::<Vec<&str>>(), post.split_whitespace().collect::<Vec<&str>>()),
+            None => continue,
+        };
+
+        if pre.len() > 4 && post.len() > 1 && BOOT_MOUNTS.contains(&pre[4]) {
+            mounts.insert(pre[4].to_string(), (pre[2].to_string(), post[0].to_string(), post[1].to_string()));
+        }
+    }
+
+    let mut out: Vec<(String, String)> = vec![];
+    for mp in BOOT_MOUNTS {
+        let (dev, fstype, source) = match mounts.get(mp) {
+            Some(mount) => mount,
+            None => continue,
+        };
+
+        // Some filesystems (e.g. btrfs) have anonymous device numbers, so the source device is used
+        let dev = match Path::new(SYS_DEV_BLOCK).join(dev).exists() {
+            true => dev.to_owned(),
+            false => get_device_number(source).unwrap_or_else(|| dev.to_owned()),
+        };
+
+        let mut modules = vec![fstype.to_owned()];
+        get_block_device_modules(&dev, &mut modules);
+        out.extend(modules.into_iter().map(|m| (m, mp.to_string())));
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob_to_regex, is_wildcard, version_cmp};
+    use regex::Regex;
+    use std::cmp::Ordering;
+
+    fn glob_matches(glob: &str, name: &str) -> bool {
+        Regex::new(&format!("^{}$", glob_to_regex(glob))).unwrap().is_match(name)
+    }
+
+    #[test]
+    fn wildcards_are_detected() {
+        for glob in ["snd-*", "linux-modules-?.?", "nf_conntrack_[a-z]", "kernel/**"] {
+            assert!(is_wildcard(glob), "{}", glob);
+        }
+        assert!(!is_wildcard("linux-modules-6.1.0-13-amd64"));
+    }
+
+    #[test]
+    fn star_and_question_stay_within_component() {
+        assert!(glob_matches("snd-*", "snd-soc-core"));
+        assert!(glob_matches("kernel/*/tap", "kernel/net/tap"));
+        assert!(!glob_matches("kernel/*/tap", "kernel/drivers/net/tap"));
+        assert!(glob_matches("nf_?at", "nf_nat"));
+        assert!(!glob_matches("a?b", "a/b"));
+    }
+
+    #[test]
+    fn double_star_crosses_components() {
+        assert!(glob_matches("kernel/drivers/**", "kernel/drivers/net/wireless/iwlwifi"));
+        assert!(glob_matches("kernel/**/tap", "kernel/drivers/net/tap"));
+        assert!(glob_matches("kernel/**/tap", "kernel/tap"));
+        assert!(!glob_matches("kernel/**/tap", "kernel/tun"));
+    }
+
+    #[test]
+    fn classes_and_negated_classes() {
+        assert!(glob_matches("nf_conntrack_[a-z]", "nf_conntrack_h"));
+        assert!(!glob_matches("nf_conntrack_[a-z]", "nf_conntrack_1"));
+        assert!(glob_matches("nf_conntrack_[!a-z]", "nf_conntrack_1"));
+        assert!(!glob_matches("nf_conntrack_[!a-z]", "nf_conntrack_h"));
+        assert!(glob_matches("snd-hda-codec-[ch]*", "snd-hda-codec-hdmi"));
+        assert!(glob_matches("a[]]b", "a]b"));
+        assert!(glob_matches("a[[^]b", "a^b"));
+        assert!(glob_matches("a[[^]b", "a[b"));
+    }
+
+    #[test]
+    fn other_characters_are_literal() {
+        assert!(glob_matches("linux-image-6.1+deb", "linux-image-6.1+deb"));
+        assert!(!glob_matches("linux-image-6.1", "linux-image-6x1"));
+        assert!(glob_matches("(foo)|{bar}", "(foo)|{bar}"));
+    }
+
+    #[test]
+    fn versions_compare_numerically() {
+        assert_eq!(version_cmp("6.1.0-13-amd64", "6.1.0-9-amd64"), Ordering::Greater);
+        assert_eq!(version_cmp("6.10.0-1-amd64", "6.9.0-1-amd64"), Ordering::Greater);
+        assert_eq!(version_cmp("6.1.0-13-amd64", "6.1.0-13-amd64"), Ordering::Equal);
+        assert_eq!(version_cmp("6.1.0", "6.1.0-1"), Ordering::Less);
+        assert_eq!(version_cmp("6.1.0-13-amd64", "6.1.0-13-arm64"), Ordering::Less);
+        assert_eq!(version_cmp("6.01.0", "6.1.0"), Ordering::Equal);
+    }
+
+    #[test]
+    fn versions_sort_as_kernels() {
+        let mut versions = ["6.1.0-9-amd64", "5.10.0-25-amd64", "6.1.0-13-amd64", "6.10.3-1-amd64", "6.1.0-10-amd64"];
+        versions.sort_by(|a, b| version_cmp(a, b));
+        assert_eq!(versions, ["5.10.0-25-amd64", "6.1.0-9-amd64", "6.1.0-10-amd64", "6.1.0-13-amd64", "6.10.3-1-amd64"]);
+    }
+}